@@ -3,5 +3,10 @@ use std::env;
 
 fn main() {
     println!("cargo:rerun-if-changed=res/*");
-    copy_to_output("assets", &env::var("PROFILE").unwrap()).expect("Failed to copy assets folder!")
+
+    // Only the viewer needs the assets next to the executable. When the library is pulled in as a
+    // dependency there is no target folder here to copy into, which shouldn't break the build.
+    if let Err(err) = copy_to_output("assets", &env::var("PROFILE").unwrap()) {
+        println!("cargo:warning=Failed to copy assets folder: {err}");
+    }
 }
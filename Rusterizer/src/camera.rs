@@ -1,7 +1,10 @@
 use std::f32::consts::PI;
 
+#[cfg(feature = "viewer")]
 use minifb::{Key, MouseButton, MouseMode, Window};
 
+use glam::Mat4;
+
 use crate::structs::Transform;
 
 pub struct Camera {
@@ -15,22 +18,32 @@ pub struct Camera {
 }
 
 impl Camera {
-    pub fn new(
-        window: &Window,
-        transform: Transform,
-        move_speed: f32,
-        mouse_sensitivity: f32,
-    ) -> Self {
+    pub fn new(transform: Transform, move_speed: f32, mouse_sensitivity: f32) -> Self {
         Camera {
             transform,
             move_speed,
             mouse_sensitivity,
-            mouse_pos_old: window.get_mouse_pos(MouseMode::Pass).unwrap(),
+            mouse_pos_old: (0.0, 0.0),
             pitch: 0.0,
             yaw: 0.0,
-            should_skip_mouse_update: false,
+            // The first mouse update only grabs the cursor position, there is no window to ask yet
+            should_skip_mouse_update: true,
         }
     }
+
+    // Pitch is clamped just short of straight up/down so the view matrix never degenerates
+    pub fn set_pitch_yaw(&mut self, pitch: f32, yaw: f32) {
+        self.pitch = pitch.clamp(-PI * 0.4999, PI * 0.4999);
+        self.yaw = yaw;
+        self.transform.rotation =
+            glam::Quat::from_euler(glam::EulerRot::YXZ, self.yaw, self.pitch, 0.0);
+    }
+
+    pub fn view_matrix(&self) -> Mat4 {
+        self.transform.view_matrix()
+    }
+
+    #[cfg(feature = "viewer")]
     pub fn update(&mut self, window: &Window, delta_time: f32) {
         // Moving forwards, backwards, left and right
         if window.is_key_down(Key::A) {
@@ -72,11 +85,10 @@ impl Camera {
 
             // If the mouse position is a specific high value, that means we're still settling in after starting to hold right click
             if !self.should_skip_mouse_update {
-                self.pitch -= delta_mouse.1 * self.mouse_sensitivity;
-                self.pitch = self.pitch.clamp(-PI * 0.4999, PI * 0.4999);
-                self.yaw -= delta_mouse.0 * self.mouse_sensitivity;
-                self.transform.rotation =
-                    glam::Quat::from_euler(glam::EulerRot::YXZ, self.yaw, self.pitch, 0.0)
+                self.set_pitch_yaw(
+                    self.pitch - delta_mouse.1 * self.mouse_sensitivity,
+                    self.yaw - delta_mouse.0 * self.mouse_sensitivity,
+                );
             } else {
                 self.should_skip_mouse_update = false;
             }
@@ -0,0 +1,131 @@
+use glam::Mat4;
+
+use crate::pipeline::CompareFunction;
+
+// Which way depth runs between the near and far plane. The depth buffer stores z/w after the
+// projection, which is 0..1 either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DepthConvention {
+    // 0 at the near plane and 1 at the far plane, what Mat4::perspective_rh gives. Most of the float
+    // precision ends up close to the camera, so distant surfaces can start fighting
+    #[default]
+    Standard,
+    // 1 at the near plane and 0 at the far plane. The precision of floats near 0 cancels out the
+    // 1/z curve, so precision stays about the same at every distance. Needs a projection from
+    // DepthConvention::perspective or orthographic
+    ReversedZ,
+}
+
+impl DepthConvention {
+    // The depth of the far plane, which is also what the depth buffer should be cleared to
+    pub fn far_depth(self) -> f32 {
+        match self {
+            DepthConvention::Standard => 1.0,
+            DepthConvention::ReversedZ => 0.0,
+        }
+    }
+
+    // The depth test that keeps the nearest pixel
+    pub fn nearer_or_equal(self) -> CompareFunction {
+        match self {
+            DepthConvention::Standard => CompareFunction::LessEqual,
+            DepthConvention::ReversedZ => CompareFunction::GreaterEqual,
+        }
+    }
+
+    // Moves a depth value towards the camera, by `amount` in depth units
+    pub fn towards_near(self, depth: f32, amount: f32) -> f32 {
+        match self {
+            DepthConvention::Standard => depth - amount,
+            DepthConvention::ReversedZ => depth + amount,
+        }
+    }
+
+    // A right handed perspective projection using this convention. Without a far plane, nothing is
+    // ever too far away to be drawn
+    pub fn perspective(
+        self,
+        fov_y_radians: f32,
+        aspect_ratio: f32,
+        near: f32,
+        far: Option<f32>,
+    ) -> Mat4 {
+        match (self, far) {
+            (DepthConvention::Standard, Some(far)) => {
+                Mat4::perspective_rh(fov_y_radians, aspect_ratio, near, far)
+            }
+            (DepthConvention::Standard, None) => {
+                Mat4::perspective_infinite_rh(fov_y_radians, aspect_ratio, near)
+            }
+            (DepthConvention::ReversedZ, Some(far)) => {
+                Mat4::perspective_rh(fov_y_radians, aspect_ratio, far, near)
+            }
+            (DepthConvention::ReversedZ, None) => {
+                Mat4::perspective_infinite_reverse_rh(fov_y_radians, aspect_ratio, near)
+            }
+        }
+    }
+
+    // A right handed orthographic projection using this convention
+    pub fn orthographic(
+        self,
+        left: f32,
+        right: f32,
+        bottom: f32,
+        top: f32,
+        near: f32,
+        far: f32,
+    ) -> Mat4 {
+        match self {
+            DepthConvention::Standard => Mat4::orthographic_rh(left, right, bottom, top, near, far),
+            DepthConvention::ReversedZ => {
+                Mat4::orthographic_rh(left, right, bottom, top, far, near)
+            }
+        }
+    }
+}
+
+// Emulates the precision of common depth buffer formats. The depth buffer itself is always f32,
+// so this saves no memory, but every depth value is rounded to what a 24 or 16 bit fixed point
+// format could represent before it is tested and written, which shows the same z-fighting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthPrecision {
+    Float32,
+    Unorm24,
+    Unorm16,
+}
+
+impl DepthPrecision {
+    // Rounds a depth value to this precision
+    pub fn quantize(self, depth: f32) -> f32 {
+        let max = match self {
+            DepthPrecision::Float32 => return depth,
+            DepthPrecision::Unorm24 => ((1 << 24) - 1) as f32,
+            DepthPrecision::Unorm16 => ((1 << 16) - 1) as f32,
+        };
+        (depth.clamp(0.0, 1.0) * max).round() / max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantize_rounds_to_fixed_point_steps() {
+        let step16 = 1.0 / 65535.0;
+        assert_eq!(DepthPrecision::Float32.quantize(0.1234567), 0.1234567);
+        assert_eq!(DepthPrecision::Unorm16.quantize(0.5), 32768.0 * step16);
+        // Values closer together than a step end up equal, which is what makes surfaces fight
+        assert_eq!(
+            DepthPrecision::Unorm16.quantize(0.5),
+            DepthPrecision::Unorm16.quantize(0.5 + step16 * 0.25)
+        );
+        assert_ne!(
+            DepthPrecision::Unorm24.quantize(0.5),
+            DepthPrecision::Unorm24.quantize(0.5 + step16 * 0.25)
+        );
+        assert_eq!(DepthPrecision::Unorm24.quantize(1.5), 1.0);
+        assert_eq!(DepthPrecision::Unorm24.quantize(-0.5), 0.0);
+    }
+}
@@ -1,4 +1,4 @@
-use glam::Vec2;
+use glam::{Vec2, Vec3};
 
 pub fn index_to_coords(index: usize, width: usize) -> glam::Vec2 {
     glam::vec2((index % width) as f32, (index / width) as f32)
@@ -9,10 +9,71 @@ pub fn coords_to_index(x: usize, y: usize, width: usize) -> usize {
 pub fn colour_rgb(red: u8, green: u8, blue: u8) -> u32 {
     ((red as u32) << 16) + ((green as u32) << 8) + (blue as u32)
 }
+// The inverse of colour_rgb, with the channels in 0..1
+pub fn rgb_to_vec3(colour: u32) -> Vec3 {
+    glam::vec3(
+        ((colour >> 16) & 0xFF) as f32,
+        ((colour >> 8) & 0xFF) as f32,
+        (colour & 0xFF) as f32,
+    ) / 255.0
+}
+// The sRGB transfer function, for a colour channel in 0..1
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+pub fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+// Like rgb_to_vec3 and colour_rgb, for colour buffers that hold sRGB while shading is linear
+pub fn srgb_rgb_to_linear(colour: u32) -> Vec3 {
+    let colour = rgb_to_vec3(colour);
+    glam::vec3(
+        srgb_to_linear(colour.x),
+        srgb_to_linear(colour.y),
+        srgb_to_linear(colour.z),
+    )
+}
+pub fn linear_to_srgb_rgb(colour: Vec3) -> u32 {
+    colour_rgb(
+        (linear_to_srgb(colour.x) * 255.0 + 0.5) as u8,
+        (linear_to_srgb(colour.y) * 255.0 + 0.5) as u8,
+        (linear_to_srgb(colour.z) * 255.0 + 0.5) as u8,
+    )
+}
 pub fn colour_rgba(alpha: u8, red: u8, green: u8, blue: u8) -> u32 {
     ((alpha as u32) << 24) + ((red as u32) << 16) + ((green as u32) << 8) + (blue as u32)
 }
 
+// Blue at 0, through cyan, green and yellow, to red at 1
+pub fn heatmap(t: f32) -> Vec3 {
+    let t = t.clamp(0.0, 1.0) * 4.0;
+    glam::vec3(
+        (t - 2.0).clamp(0.0, 1.0),
+        (t.min(4.0 - t)).clamp(0.0, 1.0),
+        (2.0 - t).clamp(0.0, 1.0),
+    )
+}
+
+// A colour that is unlikely to be the same for neighbouring ids
+pub fn id_colour(id: usize) -> Vec3 {
+    let hash = (id as u32).wrapping_mul(0x9E37_79B9) ^ (id as u32 >> 16);
+    let hash = hash.wrapping_mul(0x85EB_CA6B);
+    glam::vec3(
+        ((hash >> 24) & 0xFF) as f32 / 255.0,
+        ((hash >> 16) & 0xFF) as f32 / 255.0,
+        ((hash >> 8) & 0xFF) as f32 / 255.0,
+    )
+}
+
 pub fn to_argb8(a: u8, r: u8, g: u8, b: u8) -> u32 {
     (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | (b as u32)
 }
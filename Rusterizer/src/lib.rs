@@ -0,0 +1,36 @@
+#![allow(clippy::identity_op, clippy::too_many_arguments, dead_code)]
+
+pub mod camera;
+pub mod depth;
+pub mod helpers;
+pub mod lighting;
+pub mod mesh;
+pub mod pipeline;
+pub mod render_target;
+pub mod rendering;
+pub mod shader;
+pub mod shadow;
+pub mod structs;
+pub mod texture;
+pub mod triangle_queue;
+
+pub use camera::Camera;
+pub use depth::{DepthConvention, DepthPrecision};
+pub use lighting::{Light, LightKind};
+pub use mesh::{LoadError, Mesh, Model};
+pub use pipeline::{
+    BlendFactor, BlendOperation, BlendState, ColourWriteMask, CompareFunction, PipelineState,
+};
+pub use render_target::RenderTarget;
+pub use rendering::{CullMode, DebugView, Renderer, WireframeMode};
+pub use shader::{
+    DefaultFragmentShader, DefaultVertexShader, Fragment, FragmentShader, ShadingModel, Uniforms,
+    VertexShader, WORLD_POSITION_VARYING,
+};
+pub use shadow::{ShadowCascade, ShadowMap, ShadowSettings};
+pub use structs::{FragIn, Transform, Varyings, Vertex, MAX_VARYINGS};
+pub use texture::{
+    AlphaMode, ColourSpace, FilterMode, Material, MaterialTexture, Sampler, Texture, WrapMode,
+    MAX_ANISOTROPY,
+};
+pub use triangle_queue::{DrawCommand, FrameStats, RenderQueue};
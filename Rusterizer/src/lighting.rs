@@ -0,0 +1,303 @@
+use std::f32::consts::PI;
+
+use glam::Vec3;
+
+#[derive(Debug, Clone, Copy)]
+pub enum LightKind {
+    // Infinitely far away, shining along `direction`
+    Directional {
+        direction: Vec3,
+    },
+    Point {
+        position: Vec3,
+    },
+    // Full intensity inside the inner cone, fading out towards the outer cone. Angles are in
+    // radians, measured from `direction`
+    Spot {
+        position: Vec3,
+        direction: Vec3,
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Light {
+    pub kind: LightKind,
+    pub colour: Vec3,
+    pub intensity: f32,
+    // Distance at which point and spot lights have faded out completely. Without a range they
+    // fall off with the inverse square of the distance forever
+    pub range: Option<f32>,
+    // Only directional and spot lights can cast shadows, see ShadowSettings for the quality
+    pub cast_shadows: bool,
+}
+
+impl Light {
+    pub fn directional(direction: Vec3, colour: Vec3, intensity: f32) -> Self {
+        Light {
+            kind: LightKind::Directional {
+                direction: direction.normalize(),
+            },
+            colour,
+            intensity,
+            range: None,
+            cast_shadows: true,
+        }
+    }
+
+    pub fn point(position: Vec3, colour: Vec3, intensity: f32, range: Option<f32>) -> Self {
+        Light {
+            kind: LightKind::Point { position },
+            colour,
+            intensity,
+            range,
+            cast_shadows: false,
+        }
+    }
+
+    pub fn spot(
+        position: Vec3,
+        direction: Vec3,
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+        colour: Vec3,
+        intensity: f32,
+        range: Option<f32>,
+    ) -> Self {
+        Light {
+            kind: LightKind::Spot {
+                position,
+                direction: direction.normalize(),
+                inner_cone_angle,
+                outer_cone_angle,
+            },
+            colour,
+            intensity,
+            range,
+            cast_shadows: true,
+        }
+    }
+
+    // The direction towards the light from `position`, and the light arriving there
+    pub fn incoming(&self, position: Vec3) -> (Vec3, Vec3) {
+        let radiance = self.colour * self.intensity;
+        match self.kind {
+            LightKind::Directional { direction } => (-direction, radiance),
+            LightKind::Point {
+                position: light_position,
+            } => {
+                let (to_light, attenuation) = self.distance_attenuation(light_position, position);
+                (to_light, radiance * attenuation)
+            }
+            LightKind::Spot {
+                position: light_position,
+                direction,
+                inner_cone_angle,
+                outer_cone_angle,
+            } => {
+                let (to_light, attenuation) = self.distance_attenuation(light_position, position);
+
+                // Same falloff between the cones as KHR_lights_punctual
+                let cos_outer = outer_cone_angle.cos();
+                let cos_inner = inner_cone_angle.cos();
+                let scale = 1.0 / (cos_inner - cos_outer).max(0.001);
+                let cone = ((-to_light).dot(direction) * scale - cos_outer * scale).clamp(0.0, 1.0);
+                (to_light, radiance * attenuation * cone * cone)
+            }
+        }
+    }
+
+    fn distance_attenuation(&self, light_position: Vec3, position: Vec3) -> (Vec3, f32) {
+        let offset = light_position - position;
+        let distance_squared = offset.length_squared().max(f32::EPSILON);
+        let window = match self.range {
+            Some(range) => {
+                let ratio = distance_squared / (range * range);
+                (1.0 - ratio * ratio).clamp(0.0, 1.0).powi(2)
+            }
+            None => 1.0,
+        };
+        (offset.normalize_or_zero(), window / distance_squared)
+    }
+}
+
+// Diffuse and specular light arriving at a surface, using the Blinn-Phong model. Ambient light is
+// included in the diffuse part. `shadowing` gives how much of the light with a given index reaches
+// the surface
+pub fn blinn_phong(
+    lights: &[Light],
+    shadowing: impl Fn(usize) -> f32,
+    ambient_light: Vec3,
+    position: Vec3,
+    normal: Vec3,
+    to_camera: Vec3,
+    shininess: f32,
+) -> (Vec3, Vec3) {
+    let mut diffuse = ambient_light;
+    let mut specular = Vec3::ZERO;
+    for (i, light) in lights.iter().enumerate() {
+        let (to_light, radiance) = light.incoming(position);
+        let n_dot_l = normal.dot(to_light);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+        let radiance = radiance * shadowing(i);
+        diffuse += radiance * n_dot_l;
+        let half_vector = (to_light + to_camera).normalize_or_zero();
+        specular += radiance * normal.dot(half_vector).max(0.0).powf(shininess);
+    }
+    (diffuse, specular)
+}
+
+// Light leaving a surface towards the camera, using a Cook-Torrance specular term with the GGX
+// distribution, Smith-Schlick geometry and Schlick's Fresnel approximation, plus Lambert diffuse.
+// This is the metallic-roughness model from the glTF specification
+pub fn cook_torrance(
+    lights: &[Light],
+    shadowing: impl Fn(usize) -> f32,
+    ambient_light: Vec3,
+    position: Vec3,
+    normal: Vec3,
+    to_camera: Vec3,
+    albedo: Vec3,
+    metallic: f32,
+    roughness: f32,
+) -> Vec3 {
+    let metallic = metallic.clamp(0.0, 1.0);
+    let roughness = roughness.clamp(0.03, 1.0);
+    let alpha = roughness * roughness;
+    let alpha_squared = alpha * alpha;
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+
+    // Dielectrics reflect 4% head on, metals reflect in their own colour and have no diffuse
+    let f0 = Vec3::splat(0.04).lerp(albedo, metallic);
+    let diffuse_colour = albedo * (1.0 - metallic);
+    let n_dot_v = normal.dot(to_camera).max(1e-4);
+
+    let mut colour = ambient_light * (diffuse_colour + f0);
+    for (i, light) in lights.iter().enumerate() {
+        let (to_light, radiance) = light.incoming(position);
+        let n_dot_l = normal.dot(to_light);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+        let radiance = radiance * shadowing(i);
+        let half_vector = (to_light + to_camera).normalize_or_zero();
+        let n_dot_h = normal.dot(half_vector).max(0.0);
+        let v_dot_h = to_camera.dot(half_vector).max(0.0);
+
+        let fresnel = f0 + (Vec3::ONE - f0) * (1.0 - v_dot_h).powi(5);
+        let d = n_dot_h * n_dot_h * (alpha_squared - 1.0) + 1.0;
+        let distribution = alpha_squared / (PI * d * d);
+        let geometry =
+            (n_dot_v / (n_dot_v * (1.0 - k) + k)) * (n_dot_l / (n_dot_l * (1.0 - k) + k));
+        let specular = fresnel * (distribution * geometry / (4.0 * n_dot_v * n_dot_l));
+        let diffuse = (Vec3::ONE - fresnel) * diffuse_colour / PI;
+
+        // Same units as glTF viewers, a white surface facing a light of intensity 1 ends up at 1/pi
+        colour += (diffuse + specular) * radiance * n_dot_l;
+    }
+    colour
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A white light of intensity 1 shining straight down onto a surface facing up
+    fn overhead_light() -> [Light; 1] {
+        [Light::directional(-Vec3::Y, Vec3::ONE, 1.0)]
+    }
+
+    #[test]
+    fn blinn_phong_diffuse_and_specular() {
+        let lit = |lights: &[Light], to_camera: Vec3| {
+            blinn_phong(
+                lights,
+                |_| 1.0,
+                Vec3::splat(0.1),
+                Vec3::ZERO,
+                Vec3::Y,
+                to_camera,
+                32.0,
+            )
+        };
+        let (diffuse, specular) = lit(&overhead_light(), Vec3::Y);
+        assert!(diffuse.abs_diff_eq(Vec3::splat(1.1), 1e-6), "{diffuse}");
+        assert!(specular.abs_diff_eq(Vec3::ONE, 1e-6), "{specular}");
+        // The highlight falls off as the camera moves away from the reflection
+        let (_, specular) = lit(&overhead_light(), Vec3::new(1.0, 1.0, 0.0).normalize());
+        assert!(specular.x < 0.1, "{specular}");
+
+        // Point lights fall off with the distance squared, and reach nothing past their range
+        let point = |range| {
+            [Light::point(
+                Vec3::new(0.0, 2.0, 0.0),
+                Vec3::ONE,
+                1.0,
+                range,
+            )]
+        };
+        let (diffuse, _) = lit(&point(None), Vec3::Y);
+        assert!(diffuse.abs_diff_eq(Vec3::splat(0.35), 1e-6), "{diffuse}");
+        let (diffuse, specular) = lit(&point(Some(2.0)), Vec3::Y);
+        assert_eq!((diffuse, specular), (Vec3::splat(0.1), Vec3::ZERO));
+    }
+
+    fn head_on(albedo: Vec3, metallic: f32, roughness: f32, shadow: f32) -> Vec3 {
+        cook_torrance(
+            &overhead_light(),
+            |_| shadow,
+            Vec3::splat(0.1),
+            Vec3::ZERO,
+            Vec3::Y,
+            Vec3::Y,
+            albedo,
+            metallic,
+            roughness,
+        )
+    }
+
+    #[test]
+    fn cook_torrance_head_on() {
+        // Seen and lit head on, a fully rough surface has a GGX distribution of 1/pi, no
+        // geometric shadowing and a Fresnel term of exactly f0
+        let ambient = 0.1 * (1.0 + 0.04);
+        let dielectric = head_on(Vec3::ONE, 0.0, 1.0, 1.0);
+        let expected = ambient + (0.96 + 0.04 / 4.0) / PI;
+        assert!(
+            dielectric.abs_diff_eq(Vec3::splat(expected), 1e-6),
+            "{dielectric}"
+        );
+
+        // Metals have no diffuse, and reflect in their own colour
+        let metal = head_on(Vec3::X, 1.0, 1.0, 1.0);
+        let expected = Vec3::X * (0.1 + 0.25 / PI);
+        assert!(metal.abs_diff_eq(expected, 1e-6), "{metal}");
+
+        // Smoother surfaces concentrate the highlight
+        assert!(head_on(Vec3::X, 1.0, 0.5, 1.0).x > metal.x);
+    }
+
+    #[test]
+    fn cook_torrance_without_direct_light() {
+        let ambient_only = Vec3::splat(0.1 * (0.5 + 0.04));
+        let shadowed = head_on(Vec3::splat(0.5), 0.0, 1.0, 0.0);
+        assert!(shadowed.abs_diff_eq(ambient_only, 1e-6), "{shadowed}");
+
+        // Lit from behind
+        let behind = cook_torrance(
+            &overhead_light(),
+            |_| 1.0,
+            Vec3::splat(0.1),
+            Vec3::ZERO,
+            -Vec3::Y,
+            -Vec3::Y,
+            Vec3::splat(0.5),
+            0.0,
+            1.0,
+        );
+        assert!(behind.abs_diff_eq(ambient_only, 1e-6), "{behind}");
+    }
+}
@@ -1,71 +1,329 @@
-#![allow(clippy::identity_op, clippy::too_many_arguments, dead_code)]
-
-mod camera;
-mod helpers;
-mod mesh;
-mod rendering;
-mod structs;
-mod texture;
-mod triangle_queue;
-
 use std::{
-    collections::HashMap,
-    f32::{consts::PI, INFINITY},
-    path::Path,
+    io,
+    path::{Path, PathBuf},
     time::Instant,
 };
 
-use camera::Camera;
-use glam::Mat4;
-use mesh::Model;
-use minifb::{Key, Window, WindowOptions};
-use rendering::Renderer;
-use structs::Transform;
-use texture::Material;
+use clap::{Parser, ValueEnum};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use rusterizer::{
+    Camera, CullMode, DebugView, DefaultFragmentShader, DepthConvention, DepthPrecision, Light,
+    Model, RenderQueue, RenderTarget, Renderer, ShadingModel, ShadowSettings, Transform,
+    WireframeMode,
+};
+
+#[derive(Parser)]
+#[command(about = "CPU rasterizer glTF viewer")]
+struct Args {
+    /// glTF scene to load
+    #[arg(default_value = "./assets/miptest2.gltf")]
+    scene: PathBuf,
+
+    /// Width of the window or output image in pixels
+    #[arg(long, default_value_t = 1280)]
+    width: usize,
+
+    /// Height of the window or output image in pixels
+    #[arg(long, default_value_t = 720)]
+    height: usize,
+
+    /// Vertical field of view in degrees
+    #[arg(long, default_value_t = 72.0)]
+    fov: f32,
+
+    /// Near clipping plane distance
+    #[arg(long, default_value_t = 0.1)]
+    near: f32,
+
+    /// Far clipping plane distance
+    #[arg(long, default_value_t = 100.0)]
+    far: f32,
+
+    /// Put the far clipping plane at infinity, ignoring --far
+    #[arg(long)]
+    infinite_far: bool,
+
+    /// Which way depth runs from the near to the far plane
+    #[arg(long, value_enum, default_value_t = DepthConventionArg::ReversedZ)]
+    depth_convention: DepthConventionArg,
+
+    /// Rounds depth values to the precision of this format, the depth buffer itself stays 32-bit float
+    #[arg(long, value_enum, default_value_t = DepthPrecisionArg::Float32)]
+    depth_precision: DepthPrecisionArg,
+
+    /// Initial camera position
+    #[arg(long, num_args = 3, value_names = ["X", "Y", "Z"], allow_negative_numbers = true, default_values_t = [0.0, 0.0, 3.0])]
+    camera_position: Vec<f32>,
+
+    /// Initial camera pitch in radians
+    #[arg(long, allow_negative_numbers = true, default_value_t = 0.0)]
+    pitch: f32,
+
+    /// Initial camera yaw in radians
+    #[arg(long, allow_negative_numbers = true, default_value_t = 0.0)]
+    yaw: f32,
+
+    /// Which faces to cull, double sided materials are never culled
+    #[arg(long, value_enum, default_value_t = CullArg::Back)]
+    cull_mode: CullArg,
+
+    /// Lighting model for the default fragment shader
+    #[arg(long, value_enum, default_value_t = ShadingArg::CookTorrance)]
+    shading_model: ShadingArg,
+
+    /// What to draw instead of the lit scene. In the viewer, keys 1 to 9 switch between these
+    #[arg(long, value_enum, default_value_t = DebugViewArg::Lit)]
+    debug_view: DebugViewArg,
+
+    /// Draw the edges of the triangles, on their own or over the shaded scene. In the viewer, F cycles through these
+    #[arg(long, value_enum, default_value_t = WireframeArg::Off)]
+    wireframe: WireframeArg,
+
+    /// Colour of wireframe lines, as red, green and blue from 0 to 1
+    #[arg(long, num_args = 3, value_names = ["R", "G", "B"], default_values_t = [1.0, 1.0, 1.0])]
+    wireframe_colour: Vec<f32>,
+
+    /// Draw anti-aliased wireframe lines
+    #[arg(long)]
+    antialiased_lines: bool,
+
+    /// Render shadow maps for lights that cast shadows. Scenes without lights get a sun that does
+    #[arg(long)]
+    shadows: bool,
 
-const WIDTH: usize = 1280;
-const HEIGHT: usize = 720;
+    /// Width and height of every shadow map in texels
+    #[arg(long, default_value_t = 1024)]
+    shadow_resolution: usize,
+
+    /// How far surfaces are moved towards the light before the shadow lookup, in world units
+    #[arg(long, default_value_t = 0.02)]
+    shadow_bias: f32,
+
+    /// How far surfaces are moved along their normal before the shadow lookup, in shadow map texels
+    #[arg(long, default_value_t = 1.0)]
+    shadow_normal_bias: f32,
+
+    /// Radius of the PCF filter in shadow map texels, 0 gives hard shadows
+    #[arg(long, default_value_t = 1)]
+    shadow_pcf_radius: usize,
+
+    /// Number of shadow cascades for directional lights
+    #[arg(long, default_value_t = 3)]
+    shadow_cascades: usize,
+
+    /// How far from the camera directional lights cast shadows
+    #[arg(long, default_value_t = 50.0)]
+    shadow_distance: f32,
+
+    /// How far past the screen edges triangles may reach before being clipped, in screen sizes
+    #[arg(long, default_value_t = 2.0)]
+    guard_band: f32,
+
+    /// Override the max anisotropy of every texture sampler, from 1 to 16. 1 disables anisotropic filtering
+    #[arg(long)]
+    anisotropy: Option<f32>,
+
+    /// Rasterize on a single thread instead of spreading tiles over all cores
+    #[arg(long)]
+    single_threaded: bool,
+
+    /// Render a single frame without opening a window, and write it to the output file
+    #[arg(long)]
+    headless: bool,
+
+    /// Image to write the rendered frame to (PNG, or PPM for .ppm and .pnm). In windowed mode the last frame is saved on exit
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Image to write the normalised depth buffer to (PNG, or PGM for .pgm and .pnm). In windowed mode the last frame's depth is saved on exit
+    #[arg(long)]
+    depth_output: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CullArg {
+    None,
+    Back,
+    Front,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ShadingArg {
+    BlinnPhong,
+    CookTorrance,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DepthConventionArg {
+    Standard,
+    ReversedZ,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DepthPrecisionArg {
+    Float32,
+    Unorm24,
+    Unorm16,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WireframeArg {
+    Off,
+    Only,
+    Overlay,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DebugViewArg {
+    Lit,
+    UnlitAlbedo,
+    Normals,
+    Uvs,
+    Depth,
+    MipLevel,
+    Overdraw,
+    TriangleIds,
+    Wireframe,
+}
+
+// Keys 1 to 9 select the debug views in the order they are listed in
+const DEBUG_VIEW_KEYS: [(Key, DebugView); 9] = [
+    (Key::Key1, DebugView::Lit),
+    (Key::Key2, DebugView::UnlitAlbedo),
+    (Key::Key3, DebugView::Normals),
+    (Key::Key4, DebugView::Uvs),
+    (Key::Key5, DebugView::Depth),
+    (Key::Key6, DebugView::MipLevel),
+    (Key::Key7, DebugView::Overdraw),
+    (Key::Key8, DebugView::TriangleIds),
+    (Key::Key9, DebugView::Wireframe),
+];
 
 fn main() {
-    let mut renderer = Renderer {
-        projection_matrix: Mat4::IDENTITY,
-        view_matrix: Mat4::IDENTITY,
-        materials: HashMap::<String, Material>::new(),
-    };
-    let mut color_buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-    let mut depth_buffer: Vec<f32> = vec![INFINITY; WIDTH * HEIGHT];
-    let mut window =
-        Window::new("a", WIDTH, HEIGHT, WindowOptions::default()).unwrap_or_else(|e| {
-            panic!("{}", e);
-        });
+    let args = Args::parse();
 
-    // Limit to max ~60 fps update rate
-    window.limit_update_rate(Some(std::time::Duration::from_micros(1666)));
+    let mut renderer = Renderer::new();
+    renderer.multithreaded = !args.single_threaded;
+    renderer.guard_band = args.guard_band;
+    renderer.max_anisotropy = args.anisotropy;
+    renderer.cull_mode = match args.cull_mode {
+        CullArg::None => CullMode::None,
+        CullArg::Back => CullMode::Back,
+        CullArg::Front => CullMode::Front,
+    };
+    renderer.fragment_shader = Box::new(DefaultFragmentShader {
+        shading_model: match args.shading_model {
+            ShadingArg::BlinnPhong => ShadingModel::BlinnPhong,
+            ShadingArg::CookTorrance => ShadingModel::CookTorrance,
+        },
+        ..Default::default()
+    });
+    renderer.debug_view = match args.debug_view {
+        DebugViewArg::Lit => DebugView::Lit,
+        DebugViewArg::UnlitAlbedo => DebugView::UnlitAlbedo,
+        DebugViewArg::Normals => DebugView::Normals,
+        DebugViewArg::Uvs => DebugView::Uvs,
+        DebugViewArg::Depth => DebugView::Depth,
+        DebugViewArg::MipLevel => DebugView::MipLevel,
+        DebugViewArg::Overdraw => DebugView::Overdraw,
+        DebugViewArg::TriangleIds => DebugView::TriangleIds,
+        DebugViewArg::Wireframe => DebugView::Wireframe,
+    };
+    renderer.wireframe = match args.wireframe {
+        WireframeArg::Off => WireframeMode::Off,
+        WireframeArg::Only => WireframeMode::Only,
+        WireframeArg::Overlay => WireframeMode::Overlay,
+    };
+    renderer.wireframe_colour = glam::vec3(
+        args.wireframe_colour[0],
+        args.wireframe_colour[1],
+        args.wireframe_colour[2],
+    );
+    renderer.wireframe_antialiased = args.antialiased_lines;
+    renderer.shadows = ShadowSettings {
+        enabled: args.shadows,
+        resolution: args.shadow_resolution,
+        depth_bias: args.shadow_bias,
+        normal_bias: args.shadow_normal_bias,
+        pcf_radius: args.shadow_pcf_radius,
+        cascades: args.shadow_cascades,
+        max_distance: args.shadow_distance,
+    };
+    renderer.depth_convention = match args.depth_convention {
+        DepthConventionArg::Standard => DepthConvention::Standard,
+        DepthConventionArg::ReversedZ => DepthConvention::ReversedZ,
+    };
+    renderer.depth_precision = match args.depth_precision {
+        DepthPrecisionArg::Float32 => DepthPrecision::Float32,
+        DepthPrecisionArg::Unorm24 => DepthPrecision::Unorm24,
+        DepthPrecisionArg::Unorm16 => DepthPrecision::Unorm16,
+    };
+    let mut target = RenderTarget::new(args.width, args.height, renderer.depth_convention);
 
     // Load mesh
-    let mut model = Model::new();
-    model.create_from_gltf(Path::new("./assets/miptest2.gltf"), &mut renderer);
-
-    let model_transform = Transform {
-        translation: glam::vec3(0.0, 0.0, 0.0),
-        rotation: glam::Quat::from_euler(glam::EulerRot::ZYX, 0.0, 0.0, 0.0),
-        scale: glam::vec3(1.0, 1.0, 1.0),
+    let model = match Model::create_from_gltf(&args.scene, &mut renderer) {
+        Ok(model) => model,
+        Err(err) => {
+            eprintln!("Failed to load {}: {err}", args.scene.display());
+            std::process::exit(1);
+        }
     };
+    // The renderer's fallback light doesn't cast shadows, so when shadows are asked for, give
+    // scenes without lights a sun that does
+    if args.shadows && renderer.lights.is_empty() {
+        renderer.lights.push(Light::directional(
+            glam::vec3(-2.0, -1.0, 0.0),
+            glam::Vec3::ONE,
+            2.0,
+        ));
+    }
+
+    let model_transform = Transform::default();
 
     let mut camera = Camera::new(
-        &window,
         Transform {
-            translation: glam::vec3(0.0, 0.0, 3.0),
-            rotation: glam::quat(0.0, 0.0, 0.0, 1.0),
-            scale: glam::vec3(1.0, 1.0, 1.0),
+            translation: glam::vec3(
+                args.camera_position[0],
+                args.camera_position[1],
+                args.camera_position[2],
+            ),
+            ..Default::default()
         },
         5.0,
         0.005,
     );
+    camera.set_pitch_yaw(args.pitch, args.yaw);
+
+    let perspective_matrix = renderer.depth_convention.perspective(
+        args.fov.to_radians(),
+        args.width as f32 / args.height as f32,
+        args.near,
+        (!args.infinite_far).then_some(args.far),
+    );
+    renderer.set_projection_matrix(perspective_matrix);
+
+    if args.headless {
+        renderer.set_view_matrix(camera.view_matrix());
+        let mut queue = RenderQueue::new();
+        queue.submit_model(&renderer, &model, &model_transform);
+        let stats = queue.flush(&renderer, &mut target);
+        println!("{stats:?}");
+        let output = args.output.unwrap_or_else(|| PathBuf::from("output.png"));
+        if let Err(err) = save_frame(&target, Some(&output), args.depth_output.as_deref()) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut window = Window::new("a", args.width, args.height, WindowOptions::default())
+        .unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
+
+    // Limit to max ~60 fps update rate
+    window.limit_update_rate(Some(std::time::Duration::from_micros(1666)));
 
-    //camera.transform.translation = glam::vec3(10.873289, 7.532974, -14.913272);
-    //camera.pitch = -0.49;
-    //camera.yaw = -3.69;
     camera.update(&window, 0.0);
 
     // Main loop
@@ -75,36 +333,73 @@ fn main() {
         now = Instant::now();
 
         // Clear screen
-        for i in 0..color_buffer.len() {
-            color_buffer[i] = 0;
-            depth_buffer[i] = 0.0;
-        }
+        target.clear();
 
-        // println!(
-        //     "{}, {}, {}",
-        //     camera.transform.translation, camera.pitch, camera.yaw
-        // );
-        println!("frametime: {deltatime:.6} s");
+        camera.update(&window, deltatime);
+        renderer.set_view_matrix(camera.view_matrix());
+        for (key, debug_view) in DEBUG_VIEW_KEYS {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                renderer.debug_view = debug_view;
+                println!("debug view: {debug_view:?}");
+            }
+        }
+        if window.is_key_pressed(Key::F, KeyRepeat::No) {
+            renderer.wireframe = match renderer.wireframe {
+                WireframeMode::Off => WireframeMode::Overlay,
+                WireframeMode::Overlay => WireframeMode::Only,
+                WireframeMode::Only => WireframeMode::Off,
+            };
+            println!("wireframe: {:?}", renderer.wireframe);
+        }
 
-        let perspective_matrix =
-            glam::Mat4::perspective_rh(0.4 * PI, WIDTH as f32 / HEIGHT as f32, 0.1, 100.0);
+        // Draw the scene
+        let mut queue = RenderQueue::new();
+        queue.submit_model(&renderer, &model, &model_transform);
+        let stats = queue.flush(&renderer, &mut target);
 
-        camera.update(&window, deltatime);
-        renderer.set_view_matrix(camera.transform.view_matrix());
-        renderer.set_projection_matrix(perspective_matrix);
-
-        // Draw the triangle
-        renderer.draw_model(
-            &model,
-            &model_transform,
-            &mut color_buffer,
-            &mut depth_buffer,
-            WIDTH,
-            HEIGHT,
+        println!(
+            "frametime: {deltatime:.6} s, draws: {}/{}, triangles: {}",
+            stats.draws_submitted - stats.draws_culled,
+            stats.draws_submitted,
+            stats.triangles_rasterized
         );
 
         window
-            .update_with_buffer(&color_buffer, WIDTH, HEIGHT)
+            .update_with_buffer(&target.colour_buffer, target.width, target.height)
             .unwrap();
     }
+
+    if let Err(err) = save_frame(
+        &target,
+        args.output.as_deref(),
+        args.depth_output.as_deref(),
+    ) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+// Writes whichever of the frame and the depth buffer have a path, stopping at the first failure
+fn save_frame(
+    target: &RenderTarget,
+    output: Option<&Path>,
+    depth_output: Option<&Path>,
+) -> io::Result<()> {
+    let context = |what: &str, path: &Path| {
+        let message = format!("Failed to save {what} to {}", path.display());
+        move |err: io::Error| io::Error::new(err.kind(), format!("{message}: {err}"))
+    };
+    if let Some(output) = output {
+        target
+            .save_image(output)
+            .map_err(context("frame", output))?;
+        println!("Saved frame to {}", output.display());
+    }
+    if let Some(depth_output) = depth_output {
+        target
+            .save_depth_image(depth_output)
+            .map_err(context("depth", depth_output))?;
+        println!("Saved depth to {}", depth_output.display());
+    }
+    Ok(())
 }
@@ -1,14 +1,20 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, fmt, path::Path};
 
 use glam::Vec4Swizzles;
 use glam::{Mat4, Vec2, Vec3, Vec4};
 
 use gltf::buffer::Data;
+use gltf::khr_lights_punctual::Kind;
+use gltf::mesh::Mode;
 use gltf::texture::{MagFilter, MinFilter, WrappingMode};
 
+use crate::lighting::Light;
 use crate::rendering::Renderer;
+use crate::shader::normal_matrix;
 use crate::structs::Transform;
-use crate::texture::{FilterMode, Material, Sampler, WrapMode};
+use crate::texture::{
+    AlphaMode, ColourSpace, FilterMode, Material, MaterialTexture, Sampler, WrapMode,
+};
 use crate::{structs::Vertex, texture::Texture};
 
 pub struct Mesh {
@@ -19,191 +25,285 @@ pub struct Model {
     pub meshes: HashMap<String, Mesh>, // Where the u32 is the material id
 }
 
-// So what this function needs to do: &[u8] -(reinterpret)> &[SrcCompType] -(convert)> &[DstCompType]
-fn reinterpret_then_convert<SrcCompType, DstCompType>(input_buffer: &[u8]) -> Vec<DstCompType>
-where
-    DstCompType: From<SrcCompType>,
-    SrcCompType: Copy,
-{
-    // &[u8] -> &[SrcCompType]
-    let input_ptr = input_buffer.as_ptr();
-    let src_comp_buffer: &[SrcCompType] = unsafe {
-        std::slice::from_raw_parts(
-            std::mem::transmute(input_ptr),
-            input_buffer.len() / std::mem::size_of::<SrcCompType>(),
-        )
-    };
+impl Mesh {
+    // Axis aligned bounding box in model space, as (min, max)
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        for vert in &self.verts {
+            min = min.min(vert.position);
+            max = max.max(vert.position);
+        }
+        (min, max)
+    }
+}
 
-    // &[SrcCompType] -> Vec<DstCompType>
-    let mut dst_comp_vec = Vec::<DstCompType>::new();
-    for item in src_comp_buffer {
-        dst_comp_vec.push(DstCompType::from(*item));
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Parse(gltf::Error),
+    UnsupportedPrimitiveMode {
+        mesh: String,
+        mode: Mode,
+    },
+    MissingAttribute {
+        mesh: String,
+        attribute: &'static str,
+    },
+    IndexOutOfRange {
+        mesh: String,
+        index: u32,
+        vertex_count: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "failed to read glTF file: {err}"),
+            LoadError::Parse(err) => write!(f, "failed to parse glTF file: {err}"),
+            LoadError::UnsupportedPrimitiveMode { mesh, mode } => {
+                write!(
+                    f,
+                    "mesh \"{mesh}\" uses unsupported primitive mode {mode:?}"
+                )
+            }
+            LoadError::MissingAttribute { mesh, attribute } => {
+                write!(
+                    f,
+                    "mesh \"{mesh}\" is missing required attribute {attribute}"
+                )
+            }
+            LoadError::IndexOutOfRange {
+                mesh,
+                index,
+                vertex_count,
+            } => write!(
+                f,
+                "mesh \"{mesh}\" has index {index} but only {vertex_count} vertices"
+            ),
+        }
     }
+}
 
-    // Return
-    dst_comp_vec
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            LoadError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
-fn convert_gltf_buffer_to_f32(input_buffer: &[u8], accessor: &gltf::Accessor) -> Vec<f32> {
-    // Convert based on data type
-    // First we make a f64 vector (this way we can do fancy generics magic and still convert u32 to f32)
-    let values64 = match accessor.data_type() {
-        gltf::accessor::DataType::I8 => reinterpret_then_convert::<i8, f64>(input_buffer),
-        gltf::accessor::DataType::U8 => reinterpret_then_convert::<u8, f64>(input_buffer),
-        gltf::accessor::DataType::I16 => reinterpret_then_convert::<i16, f64>(input_buffer),
-        gltf::accessor::DataType::U16 => reinterpret_then_convert::<u16, f64>(input_buffer),
-        gltf::accessor::DataType::U32 => reinterpret_then_convert::<u32, f64>(input_buffer),
-        gltf::accessor::DataType::F32 => reinterpret_then_convert::<f32, f64>(input_buffer),
-    };
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
 
-    // Then we convert that to a f32 vector - this feels cursed as heck but let's ignore that, it'll be fine!
-    let mut values32 = Vec::<f32>::new();
-    values32.resize(values64.len(), 0.0);
-    for i in 0..values32.len() {
-        values32[i] = values64[i] as f32;
+impl From<gltf::Error> for LoadError {
+    fn from(err: gltf::Error) -> Self {
+        match err {
+            gltf::Error::Io(err) => LoadError::Io(err),
+            err => LoadError::Parse(err),
+        }
     }
+}
 
-    // Return
-    values32
+// Names are optional in glTF, so fall back to the index to keep keys unique
+fn node_name(node: &gltf::Node) -> String {
+    match node.name() {
+        Some(name) => name.to_string(),
+        None => format!("node_{}", node.index()),
+    }
+}
+
+fn material_name(material: &gltf::Material) -> String {
+    match (material.name(), material.index()) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(index)) => format!("material_{index}"),
+        // The default material has neither a name nor an index
+        (None, None) => String::from("None"),
+    }
+}
+
+// Turns strips and fans into a plain triangle list, so the rest of the loader only has to deal with one layout
+fn triangulate(indices: Vec<u32>, mode: Mode, mesh_name: &str) -> Result<Vec<u32>, LoadError> {
+    match mode {
+        Mode::Triangles => Ok(indices),
+        Mode::TriangleStrip => {
+            let mut triangles = Vec::new();
+            for i in 2..indices.len() {
+                // Every other triangle in a strip has flipped winding, swap two corners to undo that
+                if i % 2 == 0 {
+                    triangles.extend([indices[i - 2], indices[i - 1], indices[i]]);
+                } else {
+                    triangles.extend([indices[i - 1], indices[i - 2], indices[i]]);
+                }
+            }
+            Ok(triangles)
+        }
+        Mode::TriangleFan => {
+            let mut triangles = Vec::new();
+            for i in 2..indices.len() {
+                triangles.extend([indices[0], indices[i - 1], indices[i]]);
+            }
+            Ok(triangles)
+        }
+        mode => Err(LoadError::UnsupportedPrimitiveMode {
+            mesh: mesh_name.to_string(),
+            mode,
+        }),
+    }
 }
 
 fn create_vertex_array(
     primitive: &gltf::Primitive,
     mesh_data: &[Data],
     local_matrix: Mat4,
-) -> Mesh {
-    let mut position_vec = Vec::<Vec3>::new();
-    let mut normal_vec = Vec::<Vec3>::new();
-    let mut tangent_vec = Vec::<Vec4>::new();
-    let mut colour_vec = Vec::<Vec4>::new();
-    let mut texcoord_vec = Vec::<Vec2>::new();
-    let mut indices = Vec::<u16>::new();
-
-    // Loop over all the primitive attributes
-    for (name, accessor) in primitive.attributes() {
-        // Get buffer view
-        let bufferview = accessor.view().unwrap();
-
-        // Find location in buffer
-        let buffer_index = bufferview.buffer().index();
-        let buffer_offset = bufferview.offset();
-        let buffer_end = bufferview.offset() + bufferview.length();
-
-        // Find location in buffer
-        let buffer_base = &mesh_data[buffer_index].0;
-        let buffer_slice = buffer_base.get(buffer_offset..buffer_end).unwrap();
-
-        // Assign to the vectors
-        println!(
-            "name: {}, size: {}, count: {}",
-            name.to_string(),
-            accessor.size(),
-            accessor.count()
-        );
-        match name.to_string().as_str() {
-            "POSITION" => {
-                let values = convert_gltf_buffer_to_f32(buffer_slice, &accessor);
-                for i in (0..accessor.count() * 3).step_by(3) {
-                    let slice = &values[i..i + 3];
-                    position_vec.push(Vec3::from_slice(slice));
-                }
-            }
-            "NORMAL" => {
-                let values = convert_gltf_buffer_to_f32(buffer_slice, &accessor);
-                for i in (0..accessor.count() * 3).step_by(3) {
-                    let slice = &values[i..i + 3];
-                    normal_vec.push(Vec3::from_slice(slice));
-                }
-            }
-            "TANGENT" => {
-                let values = convert_gltf_buffer_to_f32(buffer_slice, &accessor);
-                for i in (0..accessor.count() * 4).step_by(4) {
-                    let slice = &values[i..i + 4];
-                    tangent_vec.push(Vec4::from_slice(slice));
-                }
-            }
-            "TEXCOORD_0" => {
-                let values = convert_gltf_buffer_to_f32(buffer_slice, &accessor);
-                for i in (0..accessor.count() * 2).step_by(2) {
-                    let slice = &values[i..i + 2];
-                    texcoord_vec.push(Vec2::from_slice(slice));
-                }
-            }
-            "COLOR_0" => {
-                let values = convert_gltf_buffer_to_f32(buffer_slice, &accessor);
-                for i in (0..accessor.count() * 4).step_by(4) {
-                    let slice = &values[i..i + 4];
-                    colour_vec.push(Vec4::from_slice(slice));
-                }
-            }
-            _ => {}
+    mesh_name: &str,
+) -> Result<Mesh, LoadError> {
+    // The reader takes care of strides, normalised integers and sparse accessors for us
+    let reader = primitive.reader(|buffer| mesh_data.get(buffer.index()).map(|data| &data.0[..]));
+
+    let position_vec: Vec<Vec3> = match reader.read_positions() {
+        Some(positions) => positions.map(Vec3::from).collect(),
+        None => {
+            return Err(LoadError::MissingAttribute {
+                mesh: mesh_name.to_string(),
+                attribute: "POSITION",
+            })
+        }
+    };
+    let normal_vec: Vec<Vec3> = reader
+        .read_normals()
+        .map(|normals| normals.map(Vec3::from).collect())
+        .unwrap_or_default();
+    let tangent_vec: Vec<Vec4> = reader
+        .read_tangents()
+        .map(|tangents| tangents.map(Vec4::from).collect())
+        .unwrap_or_default();
+    let texcoord_vec: Vec<Vec2> = reader
+        .read_tex_coords(0)
+        .map(|texcoords| texcoords.into_f32().map(Vec2::from).collect())
+        .unwrap_or_default();
+    let colour_vec: Vec<Vec4> = reader
+        .read_colors(0)
+        .map(|colours| colours.into_rgba_f32().map(Vec4::from).collect())
+        .unwrap_or_default();
+    // Find indices, non-indexed primitives just use every vertex in order
+    let indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..position_vec.len() as u32).collect(),
+    };
+    let indices = triangulate(indices, primitive.mode(), mesh_name)?;
+    for &index in &indices {
+        if index as usize >= position_vec.len() {
+            return Err(LoadError::IndexOutOfRange {
+                mesh: mesh_name.to_string(),
+                index,
+                vertex_count: position_vec.len(),
+            });
         }
     }
 
-    // Find indices
+    // Normal maps need tangents, so make some if the file doesn't have them
+    let has_normal_map = primitive.material().normal_texture().is_some();
+    let mut corner_tangents = Vec::new();
+    if has_normal_map
+        && tangent_vec.is_empty()
+        && normal_vec.len() == position_vec.len()
+        && texcoord_vec.len() == position_vec.len()
     {
-        // Get accessor
-        let accessor = primitive.indices().unwrap();
-
-        // Get buffer view
-        let bufferview = accessor.view().unwrap();
-
-        // Find location in buffer
-        let buffer_index = bufferview.buffer().index();
-        let buffer_offset = bufferview.offset();
-        let buffer_end = bufferview.offset() + bufferview.length();
-
-        // Find location in buffer
-        let buffer_base = &mesh_data[buffer_index].0;
-        let buffer_slice = buffer_base.get(buffer_offset..buffer_end).unwrap();
-
-        // Convert from raw buffer to f32 vec - this is incredibly cursed but it'll have to do
-        let indices_f32 = convert_gltf_buffer_to_f32(buffer_slice, &accessor);
-        for index in indices_f32 {
-            indices.push(index as u16);
-        }
+        corner_tangents = generate_tangents(&position_vec, &normal_vec, &texcoord_vec, &indices);
     }
 
+    // Mirroring flips which way the bitangent points
+    let handedness = local_matrix.determinant().signum();
+    let normal_matrix = normal_matrix(&local_matrix);
+
     // Create vertex array
     let mut mesh_out = Mesh { verts: Vec::new() };
-    for index in indices {
+    for (corner, index) in indices.into_iter().enumerate() {
+        let index = index as usize;
+
         let mut vertex = Vertex {
             position: Vec3::new(0., 0., 0.),
             normal: Vec3::new(0., 0., 0.),
-            tangent: Vec3::new(0., 0., 0.),
-            colour: Vec3::new(1., 1., 1.),
+            tangent: Vec4::new(0., 0., 0., 1.),
+            colour: Vec4::new(1., 1., 1., 1.),
             uv: Vec2::new(0., 0.),
         };
-        if !position_vec.is_empty() {
-            let pos3 = position_vec[index as usize];
-            vertex.position = (local_matrix * pos3.extend(1.0)).xyz();
+        let pos3 = position_vec[index];
+        vertex.position = (local_matrix * pos3.extend(1.0)).xyz();
+        if let Some(normal) = normal_vec.get(index) {
+            vertex.normal = (normal_matrix * *normal).normalize_or_zero();
         }
-        if !normal_vec.is_empty() {
-            vertex.normal = local_matrix.transform_vector3(normal_vec[index as usize]);
+        if let Some(tangent) = corner_tangents.get(corner).or(tangent_vec.get(index)) {
+            vertex.tangent = local_matrix
+                .transform_vector3(tangent.xyz())
+                .extend(tangent.w * handedness);
         }
-        if !tangent_vec.is_empty() {
-            vertex.tangent = local_matrix.transform_vector3(tangent_vec[index as usize].xyz());
+        if let Some(uv) = texcoord_vec.get(index) {
+            vertex.uv = *uv;
         }
-        if !texcoord_vec.is_empty() {
-            vertex.uv = texcoord_vec[index as usize];
-        }
-        if !colour_vec.is_empty() {
-            vertex.colour.x = f32::powf(colour_vec[index as usize].x, 1.0 / 2.2);
-            if vertex.colour.x > 1.0 {
-                vertex.colour.x = 1.0
-            }
-            vertex.colour.y = f32::powf(colour_vec[index as usize].y, 1.0 / 2.2);
-            if vertex.colour.y > 1.0 {
-                vertex.colour.y = 1.0
-            }
-            vertex.colour.z = f32::powf(colour_vec[index as usize].z, 1.0 / 2.2);
-            if vertex.colour.z > 1.0 {
-                vertex.colour.z = 1.0
-            }
+        // glTF vertex colours are already linear
+        if let Some(colour) = colour_vec.get(index) {
+            vertex.colour = colour.clamp(Vec4::ZERO, Vec4::ONE);
         }
         mesh_out.verts.push(vertex);
     }
-    mesh_out
+    Ok(mesh_out)
+}
+
+// Tangents generated with MikkTSpace, which glTF asks for when a file has none, so normal maps
+// baked by other tools line up. They are per corner of every triangle, because vertices on a uv seam
+// can get a different tangent in each triangle
+fn generate_tangents(
+    positions: &[Vec3],
+    normals: &[Vec3],
+    texcoords: &[Vec2],
+    indices: &[u32],
+) -> Vec<Vec4> {
+    let mut geometry = TangentGeometry {
+        positions,
+        normals,
+        texcoords,
+        indices,
+        tangents: vec![Vec4::new(0., 0., 0., 1.); indices.len()],
+    };
+    bevy_mikktspace::generate_tangents(&mut geometry);
+    geometry.tangents
+}
+
+struct TangentGeometry<'a> {
+    positions: &'a [Vec3],
+    normals: &'a [Vec3],
+    texcoords: &'a [Vec2],
+    indices: &'a [u32],
+    tangents: Vec<Vec4>,
+}
+
+impl bevy_mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.positions[self.indices[face * 3 + vert] as usize].to_array()
+    }
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.normals[self.indices[face * 3 + vert] as usize].to_array()
+    }
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.texcoords[self.indices[face * 3 + vert] as usize].to_array()
+    }
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = Vec4::from(tangent);
+    }
 }
 
 fn traverse_nodes(
@@ -211,8 +311,9 @@ fn traverse_nodes(
     mesh_data: &Vec<Data>,
     local_transform: Mat4,
     primitives_processed: &mut HashMap<String, Mesh>,
-) {
-    println!("\t\t\t{}: {}", node.index(), node.name().unwrap());
+    lights: &mut Vec<Light>,
+) -> Result<(), LoadError> {
+    let name = node_name(node);
 
     // Convert translation in GLTF model to a Mat4.
     let node_transform = Transform {
@@ -243,10 +344,9 @@ fn traverse_nodes(
         let primitives = mesh.primitives();
 
         for primitive in primitives {
-            println!("Creating vertex array for mesh {}", node.name().unwrap());
             let mut mesh_buffer_data =
-                create_vertex_array(&primitive, mesh_data, new_local_transform);
-            let material = String::from(primitive.material().name().unwrap_or("None"));
+                create_vertex_array(&primitive, mesh_data, new_local_transform, &name)?;
+            let material = material_name(&primitive.material());
             #[allow(clippy::map_entry)] // This was really annoying and made the code less readable
             if primitives_processed.contains_key(&material) {
                 let mesh: &mut Mesh = primitives_processed.get_mut(&material).unwrap();
@@ -257,138 +357,488 @@ fn traverse_nodes(
         }
     }
 
+    // If it has a light, place it where the node is. Lights shine along the node's -Z axis
+    if let Some(light) = node.light() {
+        lights.push(convert_light(&light, new_local_transform));
+    }
+
     // If it has children, process those
     for child in node.children() {
-        traverse_nodes(&child, mesh_data, new_local_transform, primitives_processed);
+        traverse_nodes(
+            &child,
+            mesh_data,
+            new_local_transform,
+            primitives_processed,
+            lights,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn convert_light(light: &gltf::khr_lights_punctual::Light, transform: Mat4) -> Light {
+    let position = transform.transform_point3(Vec3::ZERO);
+    let direction = transform.transform_vector3(-Vec3::Z);
+    let colour = Vec3::from(light.color());
+    match light.kind() {
+        Kind::Directional => Light::directional(direction, colour, light.intensity()),
+        Kind::Point => Light::point(position, colour, light.intensity(), light.range()),
+        Kind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => Light::spot(
+            position,
+            direction,
+            inner_cone_angle,
+            outer_cone_angle,
+            colour,
+            light.intensity(),
+            light.range(),
+        ),
+    }
+}
+
+// Loads a texture's image with mipmaps, and converts its sampler. Only textures holding colours
+// are sRGB, see ColourSpace
+fn load_material_texture(
+    texture: &gltf::Texture,
+    image_data: &[gltf::image::Data],
+    colour_space: ColourSpace,
+) -> MaterialTexture {
+    // Load the texture from the image data
+    let mut tex =
+        Texture::load_texture_from_gltf_image(&image_data[texture.source().index()], colour_space);
+    // Generate mipmaps
+    tex.generate_mipmaps();
+
+    // Get sampler mode
+    let gltf_sampler = texture.sampler();
+    let sampler = Sampler {
+        filter_mode_mag: match gltf_sampler.mag_filter().unwrap_or(MagFilter::Linear) {
+            MagFilter::Nearest => FilterMode::Point,
+            MagFilter::Linear => FilterMode::Linear,
+        },
+        filter_mode_min: match gltf_sampler
+            .min_filter()
+            .unwrap_or(MinFilter::LinearMipmapLinear)
+        {
+            MinFilter::Nearest
+            | MinFilter::NearestMipmapLinear
+            | MinFilter::NearestMipmapNearest => FilterMode::Point,
+            MinFilter::Linear | MinFilter::LinearMipmapLinear | MinFilter::LinearMipmapNearest => {
+                FilterMode::Linear
+            }
+        },
+        wrap_mode_s: match gltf_sampler.wrap_s() {
+            WrappingMode::ClampToEdge => WrapMode::Clamp,
+            WrappingMode::MirroredRepeat => WrapMode::Mirror,
+            WrappingMode::Repeat => WrapMode::Repeat,
+        },
+        wrap_mode_t: match gltf_sampler.wrap_t() {
+            WrappingMode::ClampToEdge => WrapMode::Clamp,
+            WrappingMode::MirroredRepeat => WrapMode::Mirror,
+            WrappingMode::Repeat => WrapMode::Repeat,
+        },
+        filter_mode_mipmap: match gltf_sampler
+            .min_filter()
+            .unwrap_or(MinFilter::LinearMipmapLinear)
+        {
+            MinFilter::Nearest | MinFilter::Linear => FilterMode::Point,
+            MinFilter::NearestMipmapNearest | MinFilter::LinearMipmapNearest => FilterMode::Point,
+            MinFilter::NearestMipmapLinear | MinFilter::LinearMipmapLinear => FilterMode::Linear,
+        },
+        mipmap_enabled: match gltf_sampler
+            .min_filter()
+            .unwrap_or(MinFilter::LinearMipmapLinear)
+        {
+            MinFilter::Nearest | MinFilter::Linear => false,
+            MinFilter::NearestMipmapNearest | MinFilter::LinearMipmapNearest => true,
+            MinFilter::NearestMipmapLinear | MinFilter::LinearMipmapLinear => true,
+        },
+        lod_bias: 0.0,
+        min_lod: 0.0,
+        max_lod: 1000.0,
+        max_anisotropy: 1.0,
+    };
+
+    MaterialTexture {
+        texture: tex,
+        sampler,
     }
 }
 
 impl Model {
-    pub(crate) fn create_from_gltf(&mut self, path: &Path, renderer: &mut Renderer) {
+    pub fn create_from_gltf(path: &Path, renderer: &mut Renderer) -> Result<Model, LoadError> {
         // Load GLTF from file
-        let gltf_file = gltf::import(path);
-        let (gltf_document, mesh_data, image_data) = gltf_file.unwrap();
+        let (gltf_document, mesh_data, image_data) = gltf::import(path)?;
+        Model::create_from_gltf_document(&gltf_document, &mesh_data, &image_data, renderer)
+    }
 
-        // Loop over each scene
-        println!("Scenes:");
+    fn create_from_gltf_document(
+        gltf_document: &gltf::Document,
+        mesh_data: &Vec<Data>,
+        image_data: &[gltf::image::Data],
+        renderer: &mut Renderer,
+    ) -> Result<Model, LoadError> {
+        let mut model = Model::new();
+
+        // Load the nodes of the default scene
         let scene = gltf_document.default_scene();
         if let Some(scene) = scene {
-            // For each scene, get the nodes
-            println!("\t{}: {}:", scene.index(), scene.name().unwrap());
-
-            // Print node debug
-            println!("\t\tNodes:");
             for node in scene.nodes() {
-                traverse_nodes(&node, &mesh_data, Mat4::IDENTITY, &mut self.meshes);
+                traverse_nodes(
+                    &node,
+                    mesh_data,
+                    Mat4::IDENTITY,
+                    &mut model.meshes,
+                    &mut renderer.lights,
+                )?;
             }
-            println!("test");
         }
 
         // Get all the textures from the GLTF
         for material in gltf_document.materials() {
-            let new_material;
-            // Get the base texture info
-            let gltf_tex_info = material.pbr_metallic_roughness().base_color_texture();
-
-            let mut tex;
-            // If there is a base texture, load it
-            if let Some(gltf_tex_info_unwrapped) = gltf_tex_info {
-                // Get image index
-                let gltf_tex = gltf_tex_info_unwrapped.texture().source().index();
-
-                // Get image data
-                let image = &image_data[gltf_tex];
-
-                // Load the texture from that image data
-                tex = Texture::load_texture_from_gltf_image(image);
-                // Generate mipmaps
-                tex.generate_mipmaps();
-
-                // Get sampler mode
-                let gltf_sampler = gltf_tex_info_unwrapped.texture().sampler();
-                let new_sampler = Sampler {
-                    filter_mode_mag: match gltf_sampler.mag_filter().unwrap_or(MagFilter::Linear) {
-                        MagFilter::Nearest => FilterMode::Point,
-                        MagFilter::Linear => FilterMode::Linear,
-                    },
-                    filter_mode_min: match gltf_sampler
-                        .min_filter()
-                        .unwrap_or(MinFilter::LinearMipmapLinear)
-                    {
-                        MinFilter::Nearest
-                        | MinFilter::NearestMipmapLinear
-                        | MinFilter::NearestMipmapNearest => FilterMode::Point,
-                        MinFilter::Linear
-                        | MinFilter::LinearMipmapLinear
-                        | MinFilter::LinearMipmapNearest => FilterMode::Linear,
-                    },
-                    wrap_mode_s: match gltf_sampler.wrap_s() {
-                        WrappingMode::ClampToEdge => WrapMode::Clamp,
-                        WrappingMode::MirroredRepeat => WrapMode::Mirror,
-                        WrappingMode::Repeat => WrapMode::Repeat,
-                    },
-                    wrap_mode_t: match gltf_sampler.wrap_t() {
-                        WrappingMode::ClampToEdge => WrapMode::Clamp,
-                        WrappingMode::MirroredRepeat => WrapMode::Mirror,
-                        WrappingMode::Repeat => WrapMode::Repeat,
-                    },
-                    filter_mode_mipmap: match gltf_sampler
-                        .min_filter()
-                        .unwrap_or(MinFilter::LinearMipmapLinear)
-                    {
-                        MinFilter::Nearest | MinFilter::Linear => FilterMode::Point,
-                        MinFilter::NearestMipmapNearest | MinFilter::LinearMipmapNearest => {
-                            FilterMode::Linear
-                        }
-                        MinFilter::NearestMipmapLinear | MinFilter::LinearMipmapLinear => {
-                            FilterMode::Linear
-                        }
-                    },
-                    mipmap_enabled: match gltf_sampler
-                        .min_filter()
-                        .unwrap_or(MinFilter::LinearMipmapLinear)
-                    {
-                        MinFilter::Nearest | MinFilter::Linear => false,
-                        MinFilter::NearestMipmapNearest | MinFilter::LinearMipmapNearest => true,
-                        MinFilter::NearestMipmapLinear | MinFilter::LinearMipmapLinear => true,
-                    },
-                };
-
-                new_material = Material {
-                    texture: tex,
-                    sampler: new_sampler,
-                };
-            }
-            // If there is no base texture, generate a white one
-            else {
-                new_material = Material {
-                    texture: Texture {
-                        width: 1,
-                        height: 1,
-                        depth: 1,
-                        data: vec![0xFFFFFFFFu32; 1],
-                        mipmap_offsets: vec![0usize; 1],
-                    },
-                    sampler: Sampler {
-                        filter_mode_mag: FilterMode::Point,
-                        filter_mode_min: FilterMode::Point,
-                        filter_mode_mipmap: FilterMode::Point,
-                        wrap_mode_s: WrapMode::Clamp,
-                        wrap_mode_t: WrapMode::Clamp,
-                        mipmap_enabled: false,
-                    },
-                };
-            }
+            let pbr = material.pbr_metallic_roughness();
+
+            // If there is a base texture, load it, otherwise use a white one
+            let base_colour = match pbr.base_color_texture() {
+                Some(info) => load_material_texture(&info.texture(), image_data, ColourSpace::Srgb),
+                None => MaterialTexture::solid(Vec4::ONE),
+            };
+
+            let new_material = Material {
+                texture: base_colour.texture,
+                sampler: base_colour.sampler,
+                double_sided: material.double_sided(),
+                base_colour_factor: Vec4::from(pbr.base_color_factor()),
+                metallic_factor: pbr.metallic_factor(),
+                roughness_factor: pbr.roughness_factor(),
+                metallic_roughness_texture: pbr.metallic_roughness_texture().map(|info| {
+                    load_material_texture(&info.texture(), image_data, ColourSpace::Linear)
+                }),
+                normal_texture: material.normal_texture().map(|info| {
+                    load_material_texture(&info.texture(), image_data, ColourSpace::Linear)
+                }),
+                normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+                alpha_mode: match material.alpha_mode() {
+                    gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                    gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                    gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+                },
+                alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+            };
 
             renderer
                 .materials
-                .insert(material.name().unwrap().to_string(), new_material);
+                .insert(material_name(&material), new_material);
         }
+
+        Ok(model)
     }
 
-    pub(crate) fn new() -> Model {
+    pub fn new() -> Model {
         Model {
             meshes: HashMap::new(),
         }
     }
 }
+
+impl Default for Model {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lighting::LightKind;
+
+    fn load_asset(name: &str) -> (Model, Renderer) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("assets")
+            .join(name);
+        let mut renderer = Renderer::new();
+        let model = Model::create_from_gltf(&path, &mut renderer).unwrap();
+        (model, renderer)
+    }
+
+    // A binary glTF with a single unnamed node holding one primitive, with the buffer embedded in
+    // the file. The primitive uses an unnamed material when `material` is set
+    fn primitive_glb(
+        mode: u32,
+        positions: &[[f32; 3]],
+        indices: Option<&[u32]>,
+        material: bool,
+    ) -> Vec<u8> {
+        let mut bin: Vec<u8> = positions
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let positions_length = bin.len();
+        if let Some(indices) = indices {
+            bin.extend(indices.iter().flat_map(|index| index.to_le_bytes()));
+        }
+
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        for &position in positions {
+            min = min.min(Vec3::from(position));
+            max = max.max(Vec3::from(position));
+        }
+        let mut attributes = format!(r#""attributes": {{ "POSITION": 0 }}, "mode": {mode}"#);
+        let mut accessors = format!(
+            r#"{{ "bufferView": 0, "componentType": 5126, "count": {}, "type": "VEC3", "min": {:?}, "max": {:?} }}"#,
+            positions.len(),
+            min.to_array(),
+            max.to_array()
+        );
+        let mut buffer_views =
+            format!(r#"{{ "buffer": 0, "byteOffset": 0, "byteLength": {positions_length} }}"#);
+        if let Some(indices) = indices {
+            attributes += r#", "indices": 1"#;
+            accessors += &format!(
+                r#", {{ "bufferView": 1, "componentType": 5125, "count": {}, "type": "SCALAR" }}"#,
+                indices.len()
+            );
+            buffer_views += &format!(
+                r#", {{ "buffer": 0, "byteOffset": {positions_length}, "byteLength": {} }}"#,
+                indices.len() * 4
+            );
+        }
+        if material {
+            attributes += r#", "material": 0"#;
+        }
+        let mut json = format!(
+            r#"{{
+                "asset": {{ "version": "2.0" }},
+                "scene": 0,
+                "scenes": [{{ "nodes": [0] }}],
+                "nodes": [{{ "mesh": 0 }}],
+                "meshes": [{{ "primitives": [{{ {attributes} }}] }}],
+                "materials": [{{}}],
+                "accessors": [{accessors}],
+                "bufferViews": [{buffer_views}],
+                "buffers": [{{ "byteLength": {} }}]
+            }}"#,
+            bin.len()
+        )
+        .into_bytes();
+
+        // Chunks have to be padded to four bytes, JSON with spaces and binary data with zeroes
+        json.resize(json.len().next_multiple_of(4), b' ');
+        bin.resize(bin.len().next_multiple_of(4), 0);
+        let mut glb = Vec::new();
+        glb.extend(b"glTF");
+        glb.extend(2u32.to_le_bytes());
+        glb.extend(((12 + 8 + json.len() + 8 + bin.len()) as u32).to_le_bytes());
+        glb.extend((json.len() as u32).to_le_bytes());
+        glb.extend(b"JSON");
+        glb.extend(json);
+        glb.extend((bin.len() as u32).to_le_bytes());
+        glb.extend(b"BIN\0");
+        glb.extend(bin);
+        glb
+    }
+
+    fn load_glb(glb: &[u8]) -> Result<(Model, Renderer), LoadError> {
+        let (document, mesh_data, image_data) = gltf::import_slice(glb)?;
+        let mut renderer = Renderer::new();
+        let model =
+            Model::create_from_gltf_document(&document, &mesh_data, &image_data, &mut renderer)?;
+        Ok((model, renderer))
+    }
+
+    // Twice the signed area of a triangle in the XY plane, positive when counter clockwise
+    fn winding(positions: &[[f32; 3]], triangle: &[u32]) -> f32 {
+        let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(positions[triangle[i] as usize]));
+        (b - a).cross(c - a).z
+    }
+
+    // A zigzag of vertices along the X axis, which makes a counter clockwise strip
+    const STRIP: [[f32; 3]; 5] = [
+        [0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 0.0, 0.0],
+        [2.0, 1.0, 0.0],
+    ];
+
+    #[test]
+    fn triangulate_passes_triangle_lists_through() {
+        let indices = vec![0, 1, 2, 2, 1, 3];
+        let triangles = triangulate(indices.clone(), Mode::Triangles, "mesh").unwrap();
+        assert_eq!(triangles, indices);
+    }
+
+    #[test]
+    fn triangulate_strip_keeps_winding() {
+        let triangles = triangulate(vec![0, 1, 2, 3, 4], Mode::TriangleStrip, "mesh").unwrap();
+        assert_eq!(triangles, [0, 1, 2, 2, 1, 3, 2, 3, 4]);
+        for triangle in triangles.chunks(3) {
+            assert!(winding(&STRIP, triangle) > 0.0, "{triangle:?}");
+        }
+    }
+
+    #[test]
+    fn triangulate_fan_keeps_winding() {
+        // The corners of a square, counter clockwise around the first one
+        let fan = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let triangles = triangulate(vec![0, 1, 2, 3], Mode::TriangleFan, "mesh").unwrap();
+        assert_eq!(triangles, [0, 1, 2, 0, 2, 3]);
+        for triangle in triangles.chunks(3) {
+            assert!(winding(&fan, triangle) > 0.0, "{triangle:?}");
+        }
+    }
+
+    #[test]
+    fn triangulate_short_strips_and_fans_are_empty() {
+        assert!(triangulate(vec![0, 1], Mode::TriangleStrip, "mesh")
+            .unwrap()
+            .is_empty());
+        assert!(triangulate(vec![], Mode::TriangleFan, "mesh")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn triangulate_rejects_lines() {
+        let result = triangulate(vec![0, 1], Mode::Lines, "mesh");
+        assert!(matches!(
+            result,
+            Err(LoadError::UnsupportedPrimitiveMode { mesh, mode: Mode::Lines }) if mesh == "mesh"
+        ));
+    }
+
+    #[test]
+    fn missing_file_is_an_io_error() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/does_not_exist.gltf");
+        let result = Model::create_from_gltf(&path, &mut Renderer::new());
+        assert!(matches!(result, Err(LoadError::Io(_))));
+    }
+
+    #[test]
+    fn loads_non_indexed_primitives() {
+        let (model, _) = load_glb(&primitive_glb(4, &STRIP[..3], None, false)).unwrap();
+        let verts = &model.meshes["None"].verts;
+        let positions: Vec<Vec3> = verts.iter().map(|vertex| vertex.position).collect();
+        assert_eq!(
+            positions,
+            STRIP[..3]
+                .iter()
+                .copied()
+                .map(Vec3::from)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn loads_non_indexed_strips() {
+        let (model, _) = load_glb(&primitive_glb(5, &STRIP, None, false)).unwrap();
+        let verts = &model.meshes["None"].verts;
+        assert_eq!(verts.len(), 9);
+        for triangle in verts.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| triangle[i].position);
+            assert!((b - a).cross(c - a).z > 0.0);
+        }
+    }
+
+    #[test]
+    fn names_unnamed_materials_by_index() {
+        let (model, renderer) =
+            load_glb(&primitive_glb(4, &STRIP[..3], Some(&[0, 1, 2]), true)).unwrap();
+        assert!(model.meshes.contains_key("material_0"));
+        assert!(renderer.materials.contains_key("material_0"));
+    }
+
+    #[test]
+    fn rejects_unsupported_primitive_modes() {
+        let result = load_glb(&primitive_glb(1, &STRIP[..2], None, false));
+        assert!(matches!(
+            result,
+            Err(LoadError::UnsupportedPrimitiveMode { mesh, mode: Mode::Lines }) if mesh == "node_0"
+        ));
+    }
+
+    #[test]
+    fn rejects_indices_past_the_end() {
+        let result = load_glb(&primitive_glb(4, &STRIP[..3], Some(&[0, 1, 3]), false));
+        assert!(matches!(
+            result,
+            Err(LoadError::IndexOutOfRange {
+                mesh,
+                index: 3,
+                vertex_count: 3,
+            }) if mesh == "node_0"
+        ));
+    }
+
+    #[test]
+    fn loads_punctual_lights() {
+        let (model, renderer) = load_asset("lights_test.gltf");
+        assert!(model.meshes.is_empty());
+        let [sun, bulb, torch] = &renderer.lights[..] else {
+            panic!("expected three lights, got {}", renderer.lights.len());
+        };
+
+        // Rotated a quarter turn around Y, so -Z ends up pointing along -X
+        let LightKind::Directional { direction } = sun.kind else {
+            panic!("{:?}", sun.kind);
+        };
+        assert!(direction.abs_diff_eq(-Vec3::X, 1e-5), "{direction}");
+        assert!(sun.colour.abs_diff_eq(glam::vec3(1.0, 0.9, 0.8), 1e-5));
+        assert_eq!(sun.intensity, 3.0);
+        assert_eq!(sun.range, None);
+
+        let LightKind::Point { position } = bulb.kind else {
+            panic!("{:?}", bulb.kind);
+        };
+        assert!(
+            position.abs_diff_eq(glam::vec3(0.0, 3.0, -4.0), 1e-5),
+            "{position}"
+        );
+        assert_eq!(bulb.colour, Vec3::ONE);
+        assert_eq!(bulb.range, Some(5.0));
+
+        // A child of a translated node, rotated to point straight down
+        let LightKind::Spot {
+            position,
+            direction,
+            inner_cone_angle,
+            outer_cone_angle,
+        } = torch.kind
+        else {
+            panic!("{:?}", torch.kind);
+        };
+        assert!(
+            position.abs_diff_eq(glam::vec3(1.0, 2.0, 0.0), 1e-5),
+            "{position}"
+        );
+        assert!(direction.abs_diff_eq(-Vec3::Y, 1e-5), "{direction}");
+        assert_eq!((inner_cone_angle, outer_cone_angle), (0.3, 0.6));
+        assert_eq!(torch.range, Some(10.0));
+    }
+
+    #[test]
+    fn generates_tangents_for_normal_maps() {
+        // A quad facing +Z without tangents, u runs along +X and v along -Y
+        let (model, renderer) = load_asset("normal_map_test.gltf");
+        assert!(renderer.materials["bumpy"].normal_texture.is_some());
+        let verts = &model.meshes.values().next().unwrap().verts;
+        assert_eq!(verts.len(), 6);
+        for vertex in verts {
+            let expected = glam::vec4(1.0, 0.0, 0.0, -1.0);
+            assert!(
+                vertex.tangent.abs_diff_eq(expected, 1e-5),
+                "{}",
+                vertex.tangent
+            );
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use glam::Vec3;
+
+use crate::texture::{AlphaMode, Material};
+
+// How a pixel's depth is compared against the depth buffer, the pixel is drawn when
+// `new_depth <compare> stored_depth` holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareFunction {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+impl CompareFunction {
+    pub fn passes(self, new_depth: f32, stored_depth: f32) -> bool {
+        match self {
+            CompareFunction::Never => false,
+            CompareFunction::Less => new_depth < stored_depth,
+            CompareFunction::Equal => new_depth == stored_depth,
+            CompareFunction::LessEqual => new_depth <= stored_depth,
+            CompareFunction::Greater => new_depth > stored_depth,
+            CompareFunction::NotEqual => new_depth != stored_depth,
+            CompareFunction::GreaterEqual => new_depth >= stored_depth,
+            CompareFunction::Always => true,
+        }
+    }
+}
+
+// What the source (the fragment shader's output) and destination (the colour buffer) get
+// multiplied by before they are combined. The colour buffer has no alpha, so there are no
+// destination alpha factors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColour,
+    OneMinusSrcColour,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstColour,
+    OneMinusDstColour,
+}
+
+// How the weighted source and destination are combined. Min and Max ignore the factors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendOperation {
+    Add,
+    // Source minus destination
+    Subtract,
+    // Destination minus source
+    ReverseSubtract,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlendState {
+    pub operation: BlendOperation,
+    pub src_factor: BlendFactor,
+    pub dst_factor: BlendFactor,
+}
+
+impl BlendState {
+    // Regular transparency, source over destination
+    pub const ALPHA: BlendState = BlendState {
+        operation: BlendOperation::Add,
+        src_factor: BlendFactor::SrcAlpha,
+        dst_factor: BlendFactor::OneMinusSrcAlpha,
+    };
+    // Like ALPHA, for shaders that already multiplied their colour by the alpha
+    pub const PREMULTIPLIED: BlendState = BlendState {
+        operation: BlendOperation::Add,
+        src_factor: BlendFactor::One,
+        dst_factor: BlendFactor::OneMinusSrcAlpha,
+    };
+    // Adds light, for glows and particles
+    pub const ADDITIVE: BlendState = BlendState {
+        operation: BlendOperation::Add,
+        src_factor: BlendFactor::One,
+        dst_factor: BlendFactor::One,
+    };
+    // Darkens what is behind, for tinted glass and decals like dirt
+    pub const MULTIPLY: BlendState = BlendState {
+        operation: BlendOperation::Add,
+        src_factor: BlendFactor::DstColour,
+        dst_factor: BlendFactor::Zero,
+    };
+
+    pub fn apply(&self, src: Vec3, src_alpha: f32, dst: Vec3) -> Vec3 {
+        let factor = |factor: BlendFactor| match factor {
+            BlendFactor::Zero => Vec3::ZERO,
+            BlendFactor::One => Vec3::ONE,
+            BlendFactor::SrcColour => src,
+            BlendFactor::OneMinusSrcColour => Vec3::ONE - src,
+            BlendFactor::SrcAlpha => Vec3::splat(src_alpha),
+            BlendFactor::OneMinusSrcAlpha => Vec3::splat(1.0 - src_alpha),
+            BlendFactor::DstColour => dst,
+            BlendFactor::OneMinusDstColour => Vec3::ONE - dst,
+        };
+        let result = match self.operation {
+            BlendOperation::Add => src * factor(self.src_factor) + dst * factor(self.dst_factor),
+            BlendOperation::Subtract => {
+                src * factor(self.src_factor) - dst * factor(self.dst_factor)
+            }
+            BlendOperation::ReverseSubtract => {
+                dst * factor(self.dst_factor) - src * factor(self.src_factor)
+            }
+            BlendOperation::Min => src.min(dst),
+            BlendOperation::Max => src.max(dst),
+        };
+        result.clamp(Vec3::ZERO, Vec3::ONE)
+    }
+}
+
+// Which channels of the colour buffer get written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColourWriteMask {
+    pub red: bool,
+    pub green: bool,
+    pub blue: bool,
+}
+
+impl ColourWriteMask {
+    pub const ALL: ColourWriteMask = ColourWriteMask {
+        red: true,
+        green: true,
+        blue: true,
+    };
+    // For depth pre-passes
+    pub const NONE: ColourWriteMask = ColourWriteMask {
+        red: false,
+        green: false,
+        blue: false,
+    };
+
+    // The bits of a colour_rgb pixel that may be written
+    pub fn bits(&self) -> u32 {
+        (if self.red { 0xFF0000 } else { 0 })
+            | (if self.green { 0x00FF00 } else { 0 })
+            | (if self.blue { 0x0000FF } else { 0 })
+    }
+}
+
+// Fixed function state for a draw, everything that happens to a pixel after the fragment shader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineState {
+    // None keeps the nearest pixel, whichever way the renderer's depth convention runs
+    pub depth_compare: Option<CompareFunction>,
+    pub depth_write: bool,
+    pub colour_write_mask: ColourWriteMask,
+    // None overwrites the colour buffer
+    pub blend: Option<BlendState>,
+}
+
+impl Default for PipelineState {
+    // Opaque, keeping the nearest pixel
+    fn default() -> Self {
+        PipelineState {
+            depth_compare: None,
+            depth_write: true,
+            colour_write_mask: ColourWriteMask::ALL,
+            blend: None,
+        }
+    }
+}
+
+impl PipelineState {
+    // The state glTF expects for a material, blended materials don't write depth
+    pub fn for_material(material: Option<&Material>) -> Self {
+        match material.map(|material| material.alpha_mode) {
+            Some(AlphaMode::Blend) => PipelineState {
+                depth_write: false,
+                blend: Some(BlendState::ALPHA),
+                ..Default::default()
+            },
+            _ => PipelineState::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_functions() {
+        use CompareFunction::*;
+        // Whether 0.25, 0.5 and 0.75 pass against a stored 0.5
+        let table = [
+            (Never, [false, false, false]),
+            (Less, [true, false, false]),
+            (Equal, [false, true, false]),
+            (LessEqual, [true, true, false]),
+            (Greater, [false, false, true]),
+            (NotEqual, [true, false, true]),
+            (GreaterEqual, [false, true, true]),
+            (Always, [true, true, true]),
+        ];
+        for (compare, expected) in table {
+            let passes = [0.25, 0.5, 0.75].map(|depth| compare.passes(depth, 0.5));
+            assert_eq!(passes, expected, "{compare:?}");
+        }
+    }
+
+    #[test]
+    fn blend_presets() {
+        let src = glam::vec3(1.0, 0.5, 0.0);
+        let dst = glam::vec3(0.2, 0.4, 0.8);
+        let blend = |state: BlendState| state.apply(src, 0.25, dst);
+        let close = |a: Vec3, b: Vec3| a.abs_diff_eq(b, 1e-6);
+        assert!(close(blend(BlendState::ALPHA), src * 0.25 + dst * 0.75));
+        // Results are clamped to 0..1
+        assert!(close(
+            blend(BlendState::PREMULTIPLIED),
+            glam::vec3(1.0, 0.8, 0.6)
+        ));
+        assert!(close(
+            blend(BlendState::ADDITIVE),
+            glam::vec3(1.0, 0.9, 0.8)
+        ));
+        assert!(close(
+            blend(BlendState::MULTIPLY),
+            glam::vec3(0.2, 0.2, 0.0)
+        ));
+    }
+
+    #[test]
+    fn colour_write_mask_bits() {
+        assert_eq!(ColourWriteMask::ALL.bits(), 0xFFFFFF);
+        assert_eq!(ColourWriteMask::NONE.bits(), 0);
+        let green = ColourWriteMask {
+            green: true,
+            ..ColourWriteMask::NONE
+        };
+        assert_eq!(green.bits(), 0x00FF00);
+    }
+}
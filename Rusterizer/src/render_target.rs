@@ -0,0 +1,298 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::depth::DepthConvention;
+use crate::helpers::*;
+
+pub struct RenderTarget {
+    pub width: usize,
+    pub height: usize,
+    pub colour_buffer: Vec<u32>,
+    pub depth_buffer: Vec<f32>,
+    pub stencil_buffer: Option<Vec<u8>>,
+    pub clear_colour: u32,
+    pub clear_depth: f32,
+    pub clear_stencil: u8,
+}
+
+impl RenderTarget {
+    // The depth buffer is cleared to the far plane of `depth_convention`, which has to match the
+    // renderer drawing into this target
+    pub fn new(width: usize, height: usize, depth_convention: DepthConvention) -> Self {
+        let clear_colour = 0;
+        let clear_depth = depth_convention.far_depth();
+        RenderTarget {
+            width,
+            height,
+            colour_buffer: vec![clear_colour; width * height],
+            depth_buffer: vec![clear_depth; width * height],
+            stencil_buffer: None,
+            clear_colour,
+            clear_depth,
+            clear_stencil: 0,
+        }
+    }
+
+    pub fn with_stencil(mut self) -> Self {
+        self.stencil_buffer = Some(vec![self.clear_stencil; self.width * self.height]);
+        self
+    }
+
+    pub fn with_clear_values(mut self, colour: u32, depth: f32, stencil: u8) -> Self {
+        self.clear_colour = colour;
+        self.clear_depth = depth;
+        self.clear_stencil = stencil;
+        self.clear();
+        self
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_colour_buffer();
+        self.clear_depth_buffer();
+        self.clear_stencil_buffer();
+    }
+
+    pub fn clear_colour_buffer(&mut self) {
+        self.colour_buffer.fill(self.clear_colour);
+    }
+
+    pub fn clear_depth_buffer(&mut self) {
+        self.depth_buffer.fill(self.clear_depth);
+    }
+
+    pub fn clear_stencil_buffer(&mut self) {
+        if let Some(stencil_buffer) = &mut self.stencil_buffer {
+            stencil_buffer.fill(self.clear_stencil);
+        }
+    }
+
+    // Resizing throws away the old contents, the buffers come back cleared
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.colour_buffer = vec![self.clear_colour; width * height];
+        self.depth_buffer = vec![self.clear_depth; width * height];
+        if self.stencil_buffer.is_some() {
+            self.stencil_buffer = Some(vec![self.clear_stencil; width * height]);
+        }
+    }
+
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some(coords_to_index(x, y, self.width))
+        } else {
+            None
+        }
+    }
+
+    pub fn colour_at(&self, x: usize, y: usize) -> Option<u32> {
+        self.index(x, y).map(|i| self.colour_buffer[i])
+    }
+
+    pub fn depth_at(&self, x: usize, y: usize) -> Option<f32> {
+        self.index(x, y).map(|i| self.depth_buffer[i])
+    }
+
+    pub fn stencil_at(&self, x: usize, y: usize) -> Option<u8> {
+        let i = self.index(x, y)?;
+        self.stencil_buffer
+            .as_ref()
+            .map(|stencil_buffer| stencil_buffer[i])
+    }
+
+    // The setters return false when the pixel is outside the target
+    pub fn set_colour(&mut self, x: usize, y: usize, colour: u32) -> bool {
+        match self.index(x, y) {
+            Some(i) => {
+                self.colour_buffer[i] = colour;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_depth(&mut self, x: usize, y: usize, depth: f32) -> bool {
+        match self.index(x, y) {
+            Some(i) => {
+                self.depth_buffer[i] = depth;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_stencil(&mut self, x: usize, y: usize, stencil: u8) -> bool {
+        match (self.index(x, y), &mut self.stencil_buffer) {
+            (Some(i), Some(stencil_buffer)) => {
+                stencil_buffer[i] = stencil;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Colour buffer as tightly packed 8-bit RGB, the layout both PNG and PPM want
+    pub fn colour_to_rgb8(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.colour_buffer.len() * 3);
+        for pixel in &self.colour_buffer {
+            rgb.push(((pixel >> 16) & 0xFF) as u8);
+            rgb.push(((pixel >> 8) & 0xFF) as u8);
+            rgb.push((pixel & 0xFF) as u8);
+        }
+        rgb
+    }
+
+    // Depth buffer normalised over the pixels that were actually drawn: nearest is white,
+    // furthest is dark grey, and pixels still at the clear value are black. The clear value is the
+    // far plane, so distance from it works for either depth convention
+    pub fn depth_to_grey8(&self) -> Vec<u8> {
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        for depth in &self.depth_buffer {
+            if *depth != self.clear_depth && depth.is_finite() {
+                let nearness = (depth - self.clear_depth).abs();
+                min = min.min(nearness);
+                max = max.max(nearness);
+            }
+        }
+        let range = (max - min).max(f32::EPSILON);
+
+        self.depth_buffer
+            .iter()
+            .map(|depth| {
+                if *depth == self.clear_depth || !depth.is_finite() {
+                    0
+                } else {
+                    let nearness = (depth - self.clear_depth).abs();
+                    (32.0 + ((nearness - min) / range) * 223.0) as u8
+                }
+            })
+            .collect()
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        write_png(
+            path,
+            self.width,
+            self.height,
+            png::ColorType::Rgb,
+            &self.colour_to_rgb8(),
+        )
+    }
+
+    pub fn save_depth_png(&self, path: &Path) -> io::Result<()> {
+        write_png(
+            path,
+            self.width,
+            self.height,
+            png::ColorType::Grayscale,
+            &self.depth_to_grey8(),
+        )
+    }
+
+    // PPM needs no dependencies at all, handy when something is off with the PNG encoder
+    pub fn save_ppm(&self, path: &Path) -> io::Result<()> {
+        write_pnm(path, "P6", self.width, self.height, &self.colour_to_rgb8())
+    }
+
+    pub fn save_depth_ppm(&self, path: &Path) -> io::Result<()> {
+        write_pnm(path, "P5", self.width, self.height, &self.depth_to_grey8())
+    }
+
+    // Picks the format from the file extension, .ppm and .pnm are written as PPM, .png or no
+    // extension at all as PNG. Anything else is an error rather than a file with the wrong contents
+    pub fn save_image(&self, path: &Path) -> io::Result<()> {
+        match extension(path).as_deref() {
+            Some("ppm") | Some("pnm") => self.save_ppm(path),
+            Some("png") | None => self.save_png(path),
+            Some(_) => Err(unsupported_extension(path)),
+        }
+    }
+
+    // Like save_image, but greyscale, so .pgm and .pnm are written as PGM
+    pub fn save_depth_image(&self, path: &Path) -> io::Result<()> {
+        match extension(path).as_deref() {
+            Some("pgm") | Some("pnm") => self.save_depth_ppm(path),
+            Some("png") | None => self.save_depth_png(path),
+            Some(_) => Err(unsupported_extension(path)),
+        }
+    }
+}
+
+fn unsupported_extension(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unsupported image format {}", path.display()),
+    )
+}
+
+// Lowercased, so .PPM is treated the same as .ppm
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+}
+
+fn write_png(
+    path: &Path,
+    width: usize,
+    height: usize,
+    colour_type: png::ColorType,
+    data: &[u8],
+) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+    encoder.set_color(colour_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(io::Error::other)?;
+    writer.write_image_data(data).map_err(io::Error::other)
+}
+
+fn write_pnm(path: &Path, magic: &str, width: usize, height: usize, data: &[u8]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "{magic}\n{width} {height}\n255\n")?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The first bytes of an image written to a file with the given name
+    fn magic(name: &str, save: impl Fn(&RenderTarget, &Path) -> io::Result<()>) -> Vec<u8> {
+        let dir = std::env::temp_dir().join(format!("rusterizer_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        save(&RenderTarget::new(2, 2, DepthConvention::default()), &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bytes[..2].to_vec()
+    }
+
+    #[test]
+    fn image_format_follows_extension() {
+        let png = &b"\x89P"[..];
+        assert_eq!(magic("colour.ppm", RenderTarget::save_image), b"P6");
+        assert_eq!(magic("colour.PNM", RenderTarget::save_image), b"P6");
+        assert_eq!(magic("colour.Png", RenderTarget::save_image), png);
+        assert_eq!(magic("colour", RenderTarget::save_image), png);
+        assert_eq!(magic("depth.PGM", RenderTarget::save_depth_image), b"P5");
+        assert_eq!(magic("depth.pnm", RenderTarget::save_depth_image), b"P5");
+        assert_eq!(magic("depth.png", RenderTarget::save_depth_image), png);
+    }
+
+    #[test]
+    fn unsupported_extensions_are_rejected() {
+        let target = RenderTarget::new(2, 2, DepthConvention::default());
+        let dir = std::env::temp_dir();
+        for name in ["frame.jpg", "frame.bmp", "colour.pgm"] {
+            let path = dir.join(name);
+            let err = target.save_image(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{name}");
+        }
+        let err = target.save_depth_image(&dir.join("depth.ppm")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
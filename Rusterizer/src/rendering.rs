@@ -1,23 +1,179 @@
-use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::ops::Add;
 use std::ops::Mul;
+use std::sync::Mutex;
 
+use glam::Mat3;
 use glam::Mat4;
+use glam::Vec2;
 use glam::Vec3;
-use glam::Vec3Swizzles;
+use glam::Vec4;
 use glam::Vec4Swizzles;
+use rayon::prelude::*;
 
+use crate::depth::{DepthConvention, DepthPrecision};
 use crate::helpers::*;
+use crate::lighting::{Light, LightKind};
 use crate::mesh::Mesh;
 use crate::mesh::Model;
+use crate::pipeline::PipelineState;
+use crate::render_target::RenderTarget;
+use crate::shader::{
+    DefaultFragmentShader, DefaultVertexShader, Fragment, FragmentShader, Uniforms, VertexShader,
+};
+use crate::shadow::{ShadowMap, ShadowSettings};
 use crate::structs::*;
-use crate::texture::Material;
+use crate::texture::{AlphaMode, Material};
+use crate::triangle_queue::{DrawCommand, RenderQueue, TileBins, TriangleQueueEntry};
+
+// How far behind the depth buffer a wireframe line may be and still get drawn, relative to its depth
+const WIREFRAME_DEPTH_BIAS: f32 = 0.005;
+
+// Lights the scene when no lights have been added, so that it is still visible. Bright enough for
+// a white surface facing it to come out light grey with Cook-Torrance. It doesn't cast shadows,
+// so scenes nobody asked shadows for don't pay for shadow maps
+const DEFAULT_LIGHTS: [Light; 1] = [Light {
+    kind: LightKind::Directional {
+        direction: Vec3::new(-0.894_427_2, -0.447_213_6, 0.0),
+    },
+    colour: Vec3::ONE,
+    intensity: 2.0,
+    range: None,
+    cast_shadows: false,
+}];
 
 pub struct Renderer {
     pub projection_matrix: Mat4,
     pub view_matrix: Mat4,
     pub materials: HashMap<String, Material>,
+    pub tile_size: usize,
+    pub multithreaded: bool,
+    pub cull_mode: CullMode,
+    // How far past the screen edges triangles may go before they get clipped, in screen sizes.
+    // 1.0 clips exactly against the frustum
+    pub guard_band: f32,
+    // Overrides the max anisotropy of every material's sampler when set
+    pub max_anisotropy: Option<f32>,
+    pub vertex_shader: Box<dyn VertexShader>,
+    pub fragment_shader: Box<dyn FragmentShader>,
+    pub debug_view: DebugView,
+    pub wireframe: WireframeMode,
+    // In linear light, like the colours the fragment shader returns
+    pub wireframe_colour: Vec3,
+    pub wireframe_antialiased: bool,
+    // Lights in world space. The glTF loader adds the lights it finds in a scene here
+    pub lights: Vec<Light>,
+    pub ambient_light: Vec3,
+    pub shadows: ShadowSettings,
+    // The projection matrix has to match the convention, see DepthConvention::perspective, and
+    // render targets need to be cleared to its far depth
+    pub depth_convention: DepthConvention,
+    pub depth_precision: DepthPrecision,
+    // Depth buffers of shadow map cascades from earlier frames, so they don't get allocated again
+    // every frame
+    shadow_depth_buffers: Mutex<Vec<Vec<f32>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullMode {
+    None,
+    Back,
+    Front,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireframeMode {
+    Off,
+    // Only the edges of the triangles, without filling them in
+    Only,
+    // The edges of the triangles drawn over the shaded scene
+    Overlay,
+}
+
+// Replaces the output of the fragment shader with something that helps diagnose the scene.
+// The fragment shader still runs, so pixels it discards stay discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    Lit,
+    UnlitAlbedo,
+    Normals,
+    Uvs,
+    // Normalised over the drawn pixels, like the depth image export
+    Depth,
+    // Mip level 0 is blue, going up to red at level 8
+    MipLevel,
+    // How often each pixel got shaded, once is blue, 9 times or more is red
+    Overdraw,
+    TriangleIds,
+    Wireframe,
+}
+
+// Shadow maps only need the depth, so their pass skips culling and the fragment shader. Casters are
+// drawn double sided, so open meshes like foliage cards still cast shadows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RasterPass {
+    Shaded,
+    DepthOnly,
+}
+
+// A triangle that has been mapped to the screen, with everything the pixel loop needs precomputed,
+// so that a triangle covering many tiles only gets set up once
+struct TriangleSetup<'a> {
+    v0: FragIn,
+    v1: FragIn,
+    v2: FragIn,
+    rec0: f32,
+    rec1: f32,
+    rec2: f32,
+    x_min: usize,
+    y_min: usize,
+    x_max: usize,
+    y_max: usize,
+    inv_area: f32,
+    // Whether each edge, opposite the vertex with the same index, is a top or left edge
+    top_left: [bool; 3],
+    // Derivatives of uv/w and 1/w along the screen axes
+    uv_dx: Vec2,
+    uv_dy: Vec2,
+    rec_dx: f32,
+    rec_dy: f32,
+    max_anisotropy: Option<f32>,
+    back_facing: bool,
+    material: Option<&'a Material>,
+    pipeline: PipelineState,
+}
+
+// A rectangle of the render target that one thread has exclusive access to. The buffers start at
+// row `y_offset`, and x_min..=x_max, y_min..=y_max is the part of those rows this tile may write to.
+pub struct Tile<'a> {
+    colour_buffer: &'a mut [u32],
+    depth_buffer: &'a mut [f32],
+    width: usize,
+    y_offset: usize,
+    x_min: usize,
+    y_min: usize,
+    x_max: usize,
+    y_max: usize,
+}
+
+impl<'a> Tile<'a> {
+    fn index(&self, x: usize, y: usize) -> usize {
+        x + (y - self.y_offset) * self.width
+    }
+}
+
+// Narrows t_min..t_max down to the part of a line that is on the positive side of a plane, given the
+// signed distances of its end points. Returns false when none of the line is left
+fn clip_line_to_plane(start: f32, end: f32, t_min: &mut f32, t_max: &mut f32) -> bool {
+    if start < 0.0 && end < 0.0 {
+        return false;
+    }
+    if start < 0.0 {
+        *t_min = t_min.max(start / (start - end));
+    } else if end < 0.0 {
+        *t_max = t_max.min(start / (start - end));
+    }
+    t_min <= t_max
 }
 
 fn lerp_bary<T: Mul<f32, Output = T> + Add<T, Output = T> + Copy>(
@@ -30,104 +186,243 @@ fn lerp_bary<T: Mul<f32, Output = T> + Add<T, Output = T> + Copy>(
     ((*v0 * bary.x) + (*v1 * bary.y) + (*v2 * bary.z)) * correction.unwrap_or(1.0)
 }
 
+// Pixels exactly on an edge only belong to the triangle if it is a top or left edge, so two
+// triangles sharing an edge never both draw it. Triangles are wound so their area is positive,
+// which makes left edges point down and top edges point left
+fn is_top_left(from: Vec2, to: Vec2) -> bool {
+    let delta = to - from;
+    delta.y > 0.0 || (delta.y == 0.0 && delta.x < 0.0)
+}
+
+fn inside_edge(edge: f32, top_left: bool) -> bool {
+    edge > 0.0 || (edge == 0.0 && top_left)
+}
+
+// The edge function with its endpoints always in the same order, so two triangles sharing an edge
+// get exactly opposite values and rounding can't leave a gap between them
+fn shared_edge_function(from: Vec2, to: Vec2, p: Vec2) -> f32 {
+    match (from.x, from.y) < (to.x, to.y) {
+        true => edge_function(from, to, p),
+        false => -edge_function(to, from, p),
+    }
+}
+
 impl Renderer {
-    pub fn draw_line(
-        pos1: glam::Vec2,
-        pos2: glam::Vec2,
-        buffer: &mut [u32],
-        width: usize,
-        height: usize,
-    ) {
-        let mut x = pos1.x as i32;
-        let mut y = pos1.y as i32;
-        let dx = (pos2.x - pos1.x).abs() as i32;
-        let dy = (pos2.y - pos1.y).abs() as i32;
-        let add_x = 1 - 2 * ((pos1.x > pos2.x) as i32);
-        let add_y = 1 - 2 * ((pos1.y > pos2.y) as i32);
-
-        //Loop
-        match dx.cmp(&dy) {
-            Ordering::Less => {
-                let mut p = 2 * dx - dy;
-                for _i in 0..dy {
-                    y += add_y;
-                    if p < 0 {
-                        p += 2 * (dx);
-                    } else {
-                        p += 2 * (dx - dy);
-                        x += add_x;
-                    }
-                    if x >= 0 && x < (width as i32) && y >= 0 && y < (height as i32) {
-                        buffer[coords_to_index(x as usize, y as usize, width)] =
-                            colour_rgb(0, 255, 0);
-                    }
-                }
+    pub fn new() -> Self {
+        Renderer {
+            projection_matrix: Mat4::IDENTITY,
+            view_matrix: Mat4::IDENTITY,
+            materials: HashMap::new(),
+            tile_size: 32,
+            multithreaded: true,
+            cull_mode: CullMode::Back,
+            guard_band: 2.0,
+            max_anisotropy: None,
+            vertex_shader: Box::new(DefaultVertexShader),
+            fragment_shader: Box::new(DefaultFragmentShader::default()),
+            debug_view: DebugView::Lit,
+            wireframe: WireframeMode::Off,
+            wireframe_colour: Vec3::ONE,
+            wireframe_antialiased: false,
+            lights: Vec::new(),
+            ambient_light: Vec3::splat(0.25),
+            shadows: ShadowSettings::default(),
+            depth_convention: DepthConvention::default(),
+            depth_precision: DepthPrecision::Float32,
+            shadow_depth_buffers: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Draws a line between two clip space positions. The line is clipped against the near and far
+    // planes and the screen edges, and depth tested against the depth buffer without writing to it
+    pub fn draw_line(&self, from: Vec4, to: Vec4, target: &mut RenderTarget) {
+        let width = target.width as f32;
+        let height = target.height as f32;
+
+        // Near and far, in clip space
+        let mut t_min: f32 = 0.0;
+        let mut t_max: f32 = 1.0;
+        for plane in [|p: Vec4| p.z, |p: Vec4| p.w - p.z] {
+            if !clip_line_to_plane(plane(from), plane(to), &mut t_min, &mut t_max) {
+                return;
             }
-            Ordering::Greater => {
-                let mut p = 2 * dy - dx;
-                for _i in 0..dx {
-                    x += add_x;
-                    if p < 0 {
-                        p += 2 * (dy);
-                    } else {
-                        p += 2 * (dy - dx);
-                        y += add_y;
-                    }
-                    if x >= 0 && x < (width as i32) && y >= 0 && y < (height as i32) {
-                        buffer[coords_to_index(x as usize, y as usize, width)] =
-                            colour_rgb(255, 0, 0);
-                    }
-                }
+        }
+        let to_screen = |p: Vec4| {
+            glam::vec4(
+                (p.x / p.w + 1.0) / 2.0 * width,
+                (-p.y / p.w + 1.0) / 2.0 * height,
+                p.z / p.w,
+                p.w,
+            )
+        };
+        let from_screen = to_screen(from.lerp(to, t_min));
+        let to_screen = to_screen(from.lerp(to, t_max));
+
+        // Screen edges, in screen space. Pixel centres are on whole coordinates
+        let (mut t_min, mut t_max) = (0.0, 1.0);
+        let delta = to_screen - from_screen;
+        for (start, end) in [
+            (from_screen.x, to_screen.x),
+            (width - 1.0 - from_screen.x, width - 1.0 - to_screen.x),
+            (from_screen.y, to_screen.y),
+            (height - 1.0 - from_screen.y, height - 1.0 - to_screen.y),
+        ] {
+            if !clip_line_to_plane(start, end, &mut t_min, &mut t_max) {
+                return;
             }
-            Ordering::Equal => {
-                for _i in 0..dy {
-                    if x >= 0 && x < (width as i32) && y >= 0 && y < (height as i32) {
-                        buffer[coords_to_index(x as usize, y as usize, width)] =
-                            colour_rgb(0, 0, 255);
-                    }
-                    y += add_y;
-                    x += add_x;
+        }
+        let start = from_screen + delta * t_min;
+        let end = from_screen + delta * t_max;
+
+        // Step one pixel at a time along the major axis
+        let delta = end - start;
+        let steps = delta.x.abs().max(delta.y.abs()).ceil().max(1.0) as usize;
+        let x_major = delta.x.abs() >= delta.y.abs();
+        let depth_at = |step: usize| (start + delta * (step as f32 / steps as f32)).z;
+        let far_depth = self.depth_convention.far_depth();
+        for step in 0..=steps {
+            let point = start + delta * (step as f32 / steps as f32);
+            let depth = point.z;
+
+            // Lines lie on the surfaces they outline, so they get some slack to not be hidden by
+            // their own triangles. Where the depth changes quickly, being off by a pixel matters
+            // more. Depth is roughly proportional to 1/distance away from the far plane, so the
+            // slack is relative to that
+            let depth_slope = (depth_at(step + 1) - depth).abs();
+            let slack = (depth - far_depth).abs() * WIREFRAME_DEPTH_BIAS + depth_slope;
+            let depth = self.depth_convention.towards_near(depth, slack);
+            if !self.wireframe_antialiased {
+                let x = point.x.round() as usize;
+                let y = point.y.round() as usize;
+                self.plot_line_pixel(x, y, depth, 1.0, target);
+                continue;
+            }
+
+            // Spread the pixel over the two nearest pixels on the minor axis, weighted by distance
+            let minor = if x_major { point.y } else { point.x };
+            let coverage = minor - minor.floor();
+            let pixels = [
+                (minor.floor() as usize, 1.0 - coverage),
+                (minor.floor() as usize + 1, coverage),
+            ];
+            for (minor, coverage) in pixels {
+                match x_major {
+                    true => self.plot_line_pixel(
+                        point.x.round() as usize,
+                        minor,
+                        depth,
+                        coverage,
+                        target,
+                    ),
+                    false => self.plot_line_pixel(
+                        minor,
+                        point.y.round() as usize,
+                        depth,
+                        coverage,
+                        target,
+                    ),
                 }
             }
         }
+    }
 
-        if x != (pos2.x as i32) || y != (pos2.y as i32) {
-            dbg!("Error! Positions are not equal");
+    fn plot_line_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        depth: f32,
+        coverage: f32,
+        target: &mut RenderTarget,
+    ) {
+        let Some(index) = target.index(x, y) else {
+            return;
+        };
+        if coverage <= 0.0 {
+            return;
         }
+        let depth = self.depth_precision.quantize(depth);
+        if !self
+            .depth_convention
+            .nearer_or_equal()
+            .passes(depth, target.depth_buffer[index])
+        {
+            return;
+        }
+
+        // Blend in linear light, like the shaded pass does
+        let existing = srgb_rgb_to_linear(target.colour_buffer[index]);
+        let colour = existing.lerp(self.wireframe_colour, coverage.min(1.0));
+        target.colour_buffer[index] = linear_to_srgb_rgb(colour);
     }
 
+    // Runs the vertex shader on the corners of a triangle, and draws its edges
     pub fn draw_triangle_wireframe(
+        &self,
         v0: Vertex,
         v1: Vertex,
         v2: Vertex,
-        colour_buffer: &mut [u32],
-        width: usize,
-        height: usize,
+        model_matrix: &Mat4,
+        target: &mut RenderTarget,
     ) {
-        Self::draw_line(
-            v0.position.xy(),
-            v1.position.xy(),
-            colour_buffer,
-            width,
-            height,
-        );
-        Self::draw_line(
-            v1.position.xy(),
-            v2.position.xy(),
-            colour_buffer,
-            width,
-            height,
-        );
-        Self::draw_line(
-            v2.position.xy(),
-            v0.position.xy(),
-            colour_buffer,
-            width,
-            height,
-        );
+        let uniforms = self.uniforms();
+        let p0 = self
+            .vertex_shader
+            .shade(&v0, model_matrix, &uniforms)
+            .position;
+        let p1 = self
+            .vertex_shader
+            .shade(&v1, model_matrix, &uniforms)
+            .position;
+        let p2 = self
+            .vertex_shader
+            .shade(&v2, model_matrix, &uniforms)
+            .position;
+        self.draw_line(p0, p1, target);
+        self.draw_line(p1, p2, target);
+        self.draw_line(p2, p0, target);
+    }
+
+    // Draws the edges of every triangle in a mesh that survives culling
+    pub fn draw_mesh_wireframe(
+        &self,
+        mesh: &Mesh,
+        model_matrix: &Mat4,
+        material: Option<&Material>,
+        target: &mut RenderTarget,
+    ) {
+        let uniforms = self.uniforms();
+        let double_sided = material.is_some_and(|material| material.double_sided);
+        for verts in mesh.verts.chunks_exact(3) {
+            let p0 = self
+                .vertex_shader
+                .shade(&verts[0], model_matrix, &uniforms)
+                .position;
+            let p1 = self
+                .vertex_shader
+                .shade(&verts[1], model_matrix, &uniforms)
+                .position;
+            let p2 = self
+                .vertex_shader
+                .shade(&verts[2], model_matrix, &uniforms)
+                .position;
+
+            // The sign of this determinant matches the sign of the triangle's area on screen, but
+            // unlike the area it also works for triangles that cross the near plane
+            let back_facing =
+                glam::Mat3::from_cols(p0.xyw(), p1.xyw(), p2.xyw()).determinant() < 0.0;
+            if !double_sided {
+                match self.cull_mode {
+                    CullMode::Back if back_facing => continue,
+                    CullMode::Front if !back_facing => continue,
+                    _ => {}
+                }
+            }
+            self.draw_line(p0, p1, target);
+            self.draw_line(p1, p2, target);
+            self.draw_line(p2, p0, target);
+        }
     }
 
-    // From [-1.0, +1.0] -> [0, screen_width or screen_height]
     fn ndc_to_screen(v: FragIn, width: usize, height: usize) -> FragIn {
         let mut v_out = v;
         v_out.position.x = (v_out.position.x + 1.0) / 2.0 * width as f32;
@@ -135,44 +430,59 @@ impl Renderer {
         v_out
     }
 
-    pub fn draw_triangle_filled(
-        v0_in: FragIn,
-        v1_in: FragIn,
-        v2_in: FragIn,
-        colour_buffer: &mut [u32],
-        depth_buffer: &mut [f32],
+    fn setup_triangle<'a>(
+        &self,
+        triangle: &TriangleQueueEntry<'a>,
         width: usize,
         height: usize,
-        material: Option<&Material>,
-    ) {
+        pass: RasterPass,
+    ) -> Option<TriangleSetup<'a>> {
         // Get mutable copies of vertices
-        let mut v0 = v0_in;
-        let mut v1 = v1_in;
-        let mut v2 = v2_in;
+        let mut v0 = triangle.v0;
+        let mut v1 = triangle.v1;
+        let mut v2 = triangle.v2;
+
+        // Map to screen
+        v0 = Self::ndc_to_screen(v0, width, height);
+        v1 = Self::ndc_to_screen(v1, width, height);
+        v2 = Self::ndc_to_screen(v2, width, height);
+
+        // Cull before doing any other work. Front faces have a positive area on screen
+        let back_facing = edge_function(v0.position.xy(), v1.position.xy(), v2.position.xy()) < 0.0;
+        let double_sided = triangle
+            .material
+            .is_some_and(|material| material.double_sided);
+        if !double_sided && pass == RasterPass::Shaded {
+            match self.cull_mode {
+                CullMode::Back if back_facing => return None,
+                CullMode::Front if !back_facing => return None,
+                _ => {}
+            }
+        }
+
+        // The pixel loop only accepts pixels where all edge functions are positive, so turn back faces around
+        if back_facing {
+            std::mem::swap(&mut v1, &mut v2);
+        }
 
         // Get reciprocals
         let rec0 = 1.0 / v0.position.w;
         let rec1 = 1.0 / v1.position.w;
         let rec2 = 1.0 / v2.position.w;
 
-        // Perspective division on all attributes
-        v0.normal *= rec0;
-        v0.tangent *= rec0;
-        v0.colour *= rec0;
-        v0.uv *= rec0;
-        v1.normal *= rec1;
-        v1.tangent *= rec1;
-        v1.colour *= rec1;
-        v1.uv *= rec1;
-        v2.normal *= rec2;
-        v2.tangent *= rec2;
-        v2.colour *= rec2;
-        v2.uv *= rec2;
-
-        // Map to screen
-        v0 = Self::ndc_to_screen(v0, width, height);
-        v1 = Self::ndc_to_screen(v1, width, height);
-        v2 = Self::ndc_to_screen(v2, width, height);
+        // Perspective division on all attributes, the position already had its divide
+        v0 = FragIn {
+            position: v0.position,
+            ..v0 * rec0
+        };
+        v1 = FragIn {
+            position: v1.position,
+            ..v1 * rec1
+        };
+        v2 = FragIn {
+            position: v2.position,
+            ..v2 * rec2
+        };
 
         // Get bounds of triangle
         let x_min =
@@ -186,261 +496,721 @@ impl Renderer {
 
         // Don't render off screen triangles
         if (y_max as i32 - y_min as i32) <= 0 {
-            return;
+            return None;
         }
         if (x_max as i32 - x_min as i32) <= 0 {
-            return;
+            return None;
         }
-        let area = edge_function(v0.position.xy(), v1.position.xy(), v2.position.xy()) * 0.5;
+        // The edge function of the whole triangle, so the barycentric coordinates add up to 1
+        let area = edge_function(v0.position.xy(), v1.position.xy(), v2.position.xy());
         let inv_area = 1.0 / area;
 
-        // Calculate mip level
-        let mut mip_level = 0.0;
-        let mut texture = None;
-        let mut is_mag = false;
-        if let Some(material) = material {
-            // Calculate the area of the part of the texture that is on screen
-            texture = Some(&material.texture);
-            let texture = texture.unwrap();
-            let texture_size = glam::vec2(texture.width as f32, texture.height as f32);
-            let texture_area = edge_function(
-                v0_in.uv * texture_size,
-                v1_in.uv * texture_size,
-                v2_in.uv * texture_size,
-            );
-            is_mag = texture_area.abs() > area.abs();
+        // Screen space derivatives of the barycentric coordinates, which are constant over the triangle.
+        // From those we get the derivatives of the perspective divided uv and 1/w, which the pixel
+        // loop turns into exact uv derivatives for picking a mip level
+        let bary_dx = glam::vec3(
+            v2.position.y - v1.position.y,
+            v0.position.y - v2.position.y,
+            v1.position.y - v0.position.y,
+        ) * inv_area;
+        let bary_dy = glam::vec3(
+            v1.position.x - v2.position.x,
+            v2.position.x - v0.position.x,
+            v0.position.x - v1.position.x,
+        ) * inv_area;
+        let uv_dx = lerp_bary(&bary_dx, &v0.uv, &v1.uv, &v2.uv, None);
+        let uv_dy = lerp_bary(&bary_dy, &v0.uv, &v1.uv, &v2.uv, None);
+        let rec_dx = bary_dx.dot(glam::vec3(rec0, rec1, rec2));
+        let rec_dy = bary_dy.dot(glam::vec3(rec0, rec1, rec2));
+        let max_anisotropy = self.max_anisotropy;
 
-            // Calculate the mip level by comparing the area of the texture pixels and the area of the screen pixels
-            let tex_area_log2 = texture_area.abs().log2();
-            let area_log2 = area.abs().log2();
-            mip_level = tex_area_log2 - area_log2;
-            mip_level *= 1.0; // Some manual tweaking to make it look better
-            mip_level = mip_level.clamp(0.0, (texture.mipmap_offsets.len() - 2) as f32);
-        }
+        Some(TriangleSetup {
+            v0,
+            v1,
+            v2,
+            rec0,
+            rec1,
+            rec2,
+            x_min,
+            y_min,
+            x_max,
+            y_max,
+            inv_area,
+            top_left: [
+                is_top_left(v1.position.xy(), v2.position.xy()),
+                is_top_left(v2.position.xy(), v0.position.xy()),
+                is_top_left(v0.position.xy(), v1.position.xy()),
+            ],
+            uv_dx,
+            uv_dy,
+            rec_dx,
+            rec_dy,
+            max_anisotropy,
+            back_facing,
+            material: triangle.material,
+            // Shadow casters always keep the nearest depth
+            pipeline: match pass {
+                RasterPass::Shaded => triangle.pipeline,
+                RasterPass::DepthOnly => PipelineState::default(),
+            },
+        })
+    }
+
+    fn rasterize_triangle(
+        &self,
+        triangle: &TriangleSetup,
+        triangle_id: usize,
+        tile: &mut Tile,
+        uniforms: &Uniforms,
+        pass: RasterPass,
+    ) {
+        let TriangleSetup {
+            v0,
+            v1,
+            v2,
+            rec0,
+            rec1,
+            rec2,
+            inv_area,
+            top_left,
+            uv_dx,
+            uv_dy,
+            rec_dx,
+            rec_dy,
+            max_anisotropy,
+            back_facing,
+            material,
+            pipeline,
+            ..
+        } = *triangle;
+
+        // Only visit the part of the bounding box that lies inside this tile
+        let x_min = triangle.x_min.max(tile.x_min);
+        let y_min = triangle.y_min.max(tile.y_min);
+        let x_max = triangle.x_max.min(tile.x_max);
+        let y_max = triangle.y_max.min(tile.y_max);
+
+        // For the wireframe overlay, dividing an edge function by the length of its edge gives the
+        // distance to that edge in pixels
+        let inv_edge_lengths = glam::vec3(
+            1.0 / (v2.position.xy() - v1.position.xy()).length(),
+            1.0 / (v0.position.xy() - v2.position.xy()).length(),
+            1.0 / (v1.position.xy() - v0.position.xy()).length(),
+        );
 
         for y in y_min..=y_max {
             for x in x_min..=x_max {
                 // Determine whether the point is on the triangle
                 let coords = glam::vec2(x as f32, y as f32);
-                let edge0 = edge_function(v1.position.xy(), v2.position.xy(), coords);
-                let edge1 = edge_function(v2.position.xy(), v0.position.xy(), coords);
-                let edge2 = edge_function(v0.position.xy(), v1.position.xy(), coords);
+                let edge0 = shared_edge_function(v1.position.xy(), v2.position.xy(), coords);
+                let edge1 = shared_edge_function(v2.position.xy(), v0.position.xy(), coords);
+                let edge2 = shared_edge_function(v0.position.xy(), v1.position.xy(), coords);
 
                 //If so, interpolate the colours of the vertex
-                if edge0 >= 0.0 && edge1 >= 0.0 && edge2 >= 0.0 {
+                if inside_edge(edge0, top_left[0])
+                    && inside_edge(edge1, top_left[1])
+                    && inside_edge(edge2, top_left[2])
+                {
                     //Get barycentric coordinates, texture coordinates, get the vertex colours, and sample the texture
                     let bary = glam::vec3(edge0 * inv_area, edge1 * inv_area, edge2 * inv_area);
                     let position = lerp_bary(&bary, &v0.position, &v1.position, &v2.position, None);
+                    let index = tile.index(x, y);
 
-                    // Calculate depth of current pixel
-                    let new_depth = position.z / position.w;
-
-                    // Make depth influence mip level
-                    mip_level *= 1.0 - new_depth;
+                    // z/w is linear in screen space, so it can be interpolated as is
+                    let new_depth = self.depth_precision.quantize(position.z);
 
                     // Depth testing
-                    if new_depth < depth_buffer[x + y * width] {
-                        continue;
-                    }
-
-                    // Frustrum culling
-                    if !(0.0..=1.0).contains(&new_depth) {
+                    if !pipeline
+                        .depth_compare
+                        .unwrap_or(self.depth_convention.nearer_or_equal())
+                        .passes(new_depth, tile.depth_buffer[index])
+                    {
                         continue;
                     }
 
                     let correction = bary.x * rec0 + bary.y * rec1 + bary.z * rec2;
                     let correction = 1.0 / correction;
-                    let tex_coords = lerp_bary(&bary, &v0.uv, &v1.uv, &v2.uv, Some(correction));
-                    let normal =
-                        lerp_bary(&bary, &v0.normal, &v1.normal, &v2.normal, Some(correction));
-                    let _tangent = lerp_bary(
-                        &bary,
-                        &v0.tangent,
-                        &v1.tangent,
-                        &v2.tangent,
-                        Some(correction),
-                    ); // not used, but included in case I have time to add normal mapping
-                    let mut colour =
-                        lerp_bary(&bary, &v0.colour, &v1.colour, &v2.colour, Some(correction));
-                    if false {
-                        colour.x = normal.x * 0.5 + 0.5;
-                        colour.y = normal.y * 0.5 + 0.5;
-                        colour.z = normal.z * 0.5 + 0.5;
-                    }
-                    if false {
-                        colour.x = 1.0;
-                        colour.y = 1.0;
-                        colour.z = 1.0;
-                    }
-                    if true {
-                        // Very basic lighting NdotL
-                        colour *= normal.dot(glam::vec3(1.0, 0.5, 0.0).normalize()) * 0.5 + 0.5;
+                    if pass == RasterPass::DepthOnly {
+                        // Masked materials cut out the same pixels as in the shaded pass, blended
+                        // ones that still write depth cast shadows where they are at least half
+                        // opaque. Only the alpha matters here, so plain isotropic filtering is plenty
+                        let cutoff = material.and_then(|material| match material.alpha_mode {
+                            AlphaMode::Opaque => None,
+                            AlphaMode::Mask => Some(material.alpha_cutoff),
+                            AlphaMode::Blend => Some(0.5),
+                        });
+                        if let (Some(material), Some(cutoff)) = (material, cutoff) {
+                            let uv = lerp_bary(&bary, &v0.uv, &v1.uv, &v2.uv, Some(correction));
+                            let colour = lerp_bary(
+                                &bary,
+                                &v0.colour,
+                                &v1.colour,
+                                &v2.colour,
+                                Some(correction),
+                            );
+                            let sample = material.texture.sample(
+                                uv,
+                                (uv_dx - uv * rec_dx) * correction,
+                                (uv_dy - uv * rec_dy) * correction,
+                                1.0,
+                                &material.sampler,
+                            );
+                            if sample.w * material.base_colour_factor.w * colour.w < cutoff {
+                                continue;
+                            }
+                        }
+                        tile.depth_buffer[index] = new_depth;
+                        continue;
                     }
-                    if let Some(tex) = texture {
-                        // Sample texture
-                        let texture_sample = tex.argb_at_uv(
-                            tex_coords.x,
-                            tex_coords.y,
-                            mip_level as usize,
-                            is_mag,
-                            material.unwrap(),
-                        );
-                        colour.x *= ((texture_sample) & 0xFF) as f32 / 255.0;
-                        colour.y *= ((texture_sample >> 8) & 0xFF) as f32 / 255.0;
-                        colour.z *= ((texture_sample >> 16) & 0xFF) as f32 / 255.0;
-                        if (((texture_sample >> 24) & 0xFF) as f32 / 255.0) < 0.5 {
-                            continue;
+                    let input = FragIn {
+                        position,
+                        ..lerp_bary(&bary, &v0, &v1, &v2, Some(correction))
+                    };
+                    let tex_coords = input.uv;
+
+                    // Quotient rule on (uv/w) / (1/w) gives the uv derivatives for this pixel
+                    let fragment = Fragment {
+                        input,
+                        back_facing,
+                        material,
+                        duv_dx: (uv_dx - tex_coords * rec_dx) * correction,
+                        duv_dy: (uv_dy - tex_coords * rec_dy) * correction,
+                        max_anisotropy,
+                    };
+                    let Some(colour) = self.fragment_shader.shade(&fragment, uniforms) else {
+                        continue;
+                    };
+                    let alpha = colour.w;
+                    let mut colour = colour.truncate();
+                    let mut overdraw = 0;
+                    match self.debug_view {
+                        DebugView::Lit | DebugView::Depth => {}
+                        DebugView::UnlitAlbedo => {
+                            colour = fragment.input.colour.truncate();
+                            if let Some(texture_sample) = fragment.sample_texture() {
+                                colour *= texture_sample.truncate();
+                            }
+                        }
+                        DebugView::Normals => {
+                            colour = fragment.shading_normal() * 0.5 + 0.5;
+                        }
+                        DebugView::Uvs => {
+                            colour = (tex_coords - tex_coords.floor()).extend(0.0);
+                        }
+                        DebugView::MipLevel => {
+                            colour = match material {
+                                Some(material) => {
+                                    let (mip_level, _) = material.texture.mip_level(
+                                        &material.sampler,
+                                        fragment.duv_dx,
+                                        fragment.duv_dy,
+                                    );
+                                    heatmap(mip_level / 8.0)
+                                }
+                                None => Vec3::splat(0.5),
+                            };
+                        }
+                        DebugView::Overdraw => {
+                            // The count is kept in the unused top byte of the colour buffer
+                            overdraw = ((tile.colour_buffer[index] >> 24) + 1).min(255);
+                            colour = heatmap((overdraw - 1) as f32 / 8.0);
+                        }
+                        DebugView::TriangleIds => {
+                            colour = id_colour(triangle_id);
+                        }
+                        DebugView::Wireframe => {
+                            let edge_distance = glam::vec3(edge0, edge1, edge2) * inv_edge_lengths;
+                            if edge_distance.min_element() < 1.0 {
+                                colour = Vec3::ONE;
+                            }
                         }
                     }
-                    //*i = texture_sample;
-                    colour_buffer[x + y * width] = colour_rgb(
-                        (colour.x * 255.0) as u8,
-                        (colour.y * 255.0) as u8,
-                        (colour.z * 255.0) as u8,
-                    );
+
+                    // Debug views replace the colour, so they aren't blended. Shading and blending
+                    // happen in linear light and the colour buffer holds sRGB, except for debug
+                    // views that show data rather than colours
+                    let existing = tile.colour_buffer[index];
+                    let colour = match self.debug_view {
+                        DebugView::Lit | DebugView::Depth => {
+                            if let Some(blend) = pipeline.blend {
+                                colour = blend.apply(colour, alpha, srgb_rgb_to_linear(existing));
+                            }
+                            linear_to_srgb_rgb(colour)
+                        }
+                        DebugView::UnlitAlbedo => linear_to_srgb_rgb(colour),
+                        _ => colour_rgb(
+                            (colour.x * 255.0) as u8,
+                            (colour.y * 255.0) as u8,
+                            (colour.z * 255.0) as u8,
+                        ),
+                    };
+                    let mask = pipeline.colour_write_mask.bits();
+                    tile.colour_buffer[index] =
+                        (colour & mask) | (existing & !mask & 0xFFFFFF) | (overdraw << 24);
                     // Write to depth buffer
-                    depth_buffer[x + y * width] = new_depth;
-                    //*i = colour_rgb((tex_coords.x * 255.0) as u8, (tex_coords.y * 255.0) as u8, 0);
+                    if pipeline.depth_write {
+                        tile.depth_buffer[index] = new_depth;
+                    }
                 }
             }
         }
     }
 
-    fn vertex_shader(&self, vert: &Vertex, model_matrix: &Mat4) -> FragIn {
-        let mut v = glam::vec4(vert.position.x, vert.position.y, vert.position.z, 1.0);
-        v = model_matrix.mul_vec4(v);
-        v = self.view_matrix.mul_vec4(v);
-        v = self.projection_matrix.mul_vec4(v);
-        FragIn {
-            position: v,
-            normal: vert.normal,
-            tangent: vert.tangent,
-            colour: vert.colour,
-            uv: vert.uv,
+    pub fn draw_triangle_filled(
+        &self,
+        v0: FragIn,
+        v1: FragIn,
+        v2: FragIn,
+        target: &mut RenderTarget,
+        material: Option<&Material>,
+    ) {
+        let triangle = TriangleQueueEntry {
+            v0,
+            v1,
+            v2,
+            material,
+            pipeline: PipelineState::for_material(material),
+        };
+        if let Some(setup) =
+            self.setup_triangle(&triangle, target.width, target.height, RasterPass::Shaded)
+        {
+            let mut tile = Tile {
+                width: target.width,
+                y_offset: 0,
+                x_min: 0,
+                y_min: 0,
+                x_max: target.width - 1,
+                y_max: target.height - 1,
+                colour_buffer: &mut target.colour_buffer,
+                depth_buffer: &mut target.depth_buffer,
+            };
+            self.rasterize_triangle(&setup, 0, &mut tile, &self.uniforms(), RasterPass::Shaded);
         }
     }
 
-    pub fn draw_mesh(
+    // Bins the triangles into tiles, then shades every row of tiles on its own thread. Within a
+    // tile the triangles are drawn in submission order, so the result doesn't depend on the
+    // number of threads, or whether we use threads at all.
+    pub fn rasterize_triangles(&self, triangles: &[TriangleQueueEntry], target: &mut RenderTarget) {
+        self.rasterize(triangles, target, &self.uniforms());
+    }
+
+    pub(crate) fn rasterize(
         &self,
-        mesh: &Mesh,
-        model_matrix: &Transform,
-        colour_buffer: &mut [u32],
+        triangles: &[TriangleQueueEntry],
+        target: &mut RenderTarget,
+        uniforms: &Uniforms,
+    ) {
+        self.rasterize_buffers(
+            triangles,
+            target.width,
+            target.height,
+            &mut target.colour_buffer,
+            &mut target.depth_buffer,
+            uniforms,
+            RasterPass::Shaded,
+        );
+    }
+
+    // Only fills in the depth buffer, for shadow maps, so there is no need for a colour buffer
+    fn rasterize_depth(
+        &self,
+        triangles: &[TriangleQueueEntry],
+        width: usize,
+        height: usize,
         depth_buffer: &mut [f32],
+        uniforms: &Uniforms,
+    ) {
+        self.rasterize_buffers(
+            triangles,
+            width,
+            height,
+            &mut [],
+            depth_buffer,
+            uniforms,
+            RasterPass::DepthOnly,
+        );
+    }
+
+    fn rasterize_buffers(
+        &self,
+        triangles: &[TriangleQueueEntry],
         width: usize,
         height: usize,
-        material: Option<&Material>,
+        colour_buffer: &mut [u32],
+        depth_buffer: &mut [f32],
+        uniforms: &Uniforms,
+        pass: RasterPass,
     ) {
-        for i in (0..mesh.verts.len()).step_by(3) {
-            // Transform vertices
-            let v0 = mesh.verts[i];
-            let v1 = mesh.verts[i + 1];
-            let v2 = mesh.verts[i + 2];
-            let v0 = self.vertex_shader(&v0, &model_matrix.trans_matrix());
-            let v1 = self.vertex_shader(&v1, &model_matrix.trans_matrix());
-            let v2 = self.vertex_shader(&v2, &model_matrix.trans_matrix());
+        if width == 0 || height == 0 {
+            return;
+        }
+        let tile_size = self.tile_size.max(1);
 
-            // Create the vector for output triangles
-            let mut new_triangles = Vec::<FragIn>::new();
+        let setups: Vec<Option<TriangleSetup>> = if self.multithreaded {
+            triangles
+                .par_iter()
+                .map(|triangle| self.setup_triangle(triangle, width, height, pass))
+                .collect()
+        } else {
+            triangles
+                .iter()
+                .map(|triangle| self.setup_triangle(triangle, width, height, pass))
+                .collect()
+        };
 
-            // Clip against near plane
-            {
-                // Check how many triangles are behind the near plane
-                let mut n_outside = 0;
-                if v0.position.z < 0.0 {
-                    n_outside += 1;
-                }
-                if v1.position.z < 0.0 {
-                    n_outside += 1;
-                }
-                if v2.position.z < 0.0 {
-                    n_outside += 1;
+        let mut bins = TileBins::new(width, height, tile_size);
+        for (i, setup) in setups.iter().enumerate() {
+            if let Some(setup) = setup {
+                bins.insert(i, setup.x_min, setup.y_min, setup.x_max, setup.y_max);
+            }
+        }
+
+        let tiles_x = bins.tiles_x;
+        let rows_per_tile = width * tile_size;
+        // The depth-only pass never touches colours, so its tiles get an empty colour buffer
+        let mut colour_rows: Vec<&mut [u32]> = match pass {
+            RasterPass::Shaded => colour_buffer.chunks_mut(rows_per_tile).collect(),
+            RasterPass::DepthOnly => (0..height.div_ceil(tile_size))
+                .map(|_| Default::default())
+                .collect(),
+        };
+        if self.multithreaded {
+            colour_rows
+                .par_iter_mut()
+                .zip(depth_buffer.par_chunks_mut(rows_per_tile))
+                .zip(bins.bins.par_chunks(tiles_x))
+                .enumerate()
+                .for_each(|(tile_y, ((colour_rows, depth_rows), row_bins))| {
+                    self.shade_tile_row(
+                        &setups,
+                        row_bins,
+                        colour_rows,
+                        depth_rows,
+                        tile_y,
+                        tile_size,
+                        width,
+                        height,
+                        uniforms,
+                        pass,
+                    )
+                });
+        } else {
+            colour_rows
+                .iter_mut()
+                .zip(depth_buffer.chunks_mut(rows_per_tile))
+                .zip(bins.bins.chunks(tiles_x))
+                .enumerate()
+                .for_each(|(tile_y, ((colour_rows, depth_rows), row_bins))| {
+                    self.shade_tile_row(
+                        &setups,
+                        row_bins,
+                        colour_rows,
+                        depth_rows,
+                        tile_y,
+                        tile_size,
+                        width,
+                        height,
+                        uniforms,
+                        pass,
+                    )
+                });
+        }
+    }
+
+    // Debug views that need the whole frame are applied once it's drawn. RenderQueue::flush does
+    // this itself, when drawing with draw_mesh or draw_triangle_filled call it at the end of the frame
+    pub fn resolve_debug_view(&self, target: &mut RenderTarget) {
+        if self.debug_view == DebugView::Depth {
+            let depth = target.depth_to_grey8();
+            for (colour, depth) in target.colour_buffer.iter_mut().zip(depth) {
+                *colour = colour_rgb(depth, depth, depth);
+            }
+        }
+    }
+
+    fn shade_tile_row(
+        &self,
+        setups: &[Option<TriangleSetup>],
+        row_bins: &[Vec<usize>],
+        colour_rows: &mut [u32],
+        depth_rows: &mut [f32],
+        tile_y: usize,
+        tile_size: usize,
+        width: usize,
+        height: usize,
+        uniforms: &Uniforms,
+        pass: RasterPass,
+    ) {
+        for (tile_x, bin) in row_bins.iter().enumerate() {
+            let mut tile = Tile {
+                colour_buffer: &mut *colour_rows,
+                depth_buffer: &mut *depth_rows,
+                width,
+                y_offset: tile_y * tile_size,
+                x_min: tile_x * tile_size,
+                y_min: tile_y * tile_size,
+                x_max: ((tile_x + 1) * tile_size).min(width) - 1,
+                y_max: ((tile_y + 1) * tile_size).min(height) - 1,
+            };
+            for &i in bin {
+                if let Some(setup) = &setups[i] {
+                    self.rasterize_triangle(setup, i, &mut tile, uniforms, pass);
                 }
+            }
+        }
+    }
 
-                // n_outside = 0;
+    pub fn uniforms(&self) -> Uniforms<'_> {
+        Uniforms {
+            view_matrix: self.view_matrix,
+            projection_matrix: self.projection_matrix,
+            camera_position: self.view_matrix.inverse().w_axis.truncate(),
+            lights: match self.lights.is_empty() {
+                true => &DEFAULT_LIGHTS,
+                false => &self.lights,
+            },
+            ambient_light: self.ambient_light,
+            shadow_maps: &[],
+            normal_matrix: Mat3::IDENTITY,
+            handedness: 1.0,
+        }
+    }
 
-                match n_outside {
-                    // If all vertices are in front of the near plane, don't do anything
-                    0 => {
-                        new_triangles.push(v0);
-                        new_triangles.push(v1);
-                        new_triangles.push(v2);
-                    }
+    // Renders the depth of the draws as seen from every light that casts shadows. The result has
+    // one entry per light in the uniforms, for Uniforms::shadow_maps
+    pub(crate) fn render_shadow_maps(&self, draws: &[&DrawCommand]) -> Vec<Option<ShadowMap>> {
+        let uniforms = self.uniforms();
+        if !self.shadows.enabled || !uniforms.lights.iter().any(|light| light.cast_shadows) {
+            return Vec::new();
+        }
 
-                    // If one vertex is behind the near plane, clip it, we should get 2 triangles back
-                    1 => {
-                        // Order vertices so that C is always the one behind the near clipping plane
-                        let a;
-                        let b;
-                        let c;
-                        if v0.position.z < 0.0 {
-                            a = v1;
-                            b = v2;
-                            c = v0;
-                        } else if v1.position.z < 0.0 {
-                            a = v2;
-                            b = v0;
-                            c = v1;
-                        } else {
-                            // if v2.position.z < 0.0
-                            a = v0;
-                            b = v1;
-                            c = v2;
-                        }
+        // Draws that don't write depth, like glows and glass, don't cast shadows either
+        let casters: Vec<&DrawCommand> = draws
+            .iter()
+            .copied()
+            .filter(|draw| draw.pipeline.depth_write)
+            .collect();
 
-                        // Calculate mid_AC and mid_BC
-                        let t_mid_ac = (0.0 - a.position.z) / (c.position.z - a.position.z);
-                        let t_mid_bc = (0.0 - b.position.z) / (c.position.z - b.position.z);
-                        let mid_ac = a.lerp(c, t_mid_ac);
-                        let mid_bc = b.lerp(c, t_mid_bc);
-
-                        // Triangle 1
-                        new_triangles.push(a);
-                        new_triangles.push(b);
-                        new_triangles.push(mid_ac);
-
-                        // Triangle 2
-                        new_triangles.push(mid_ac);
-                        new_triangles.push(b);
-                        new_triangles.push(mid_bc);
+        // The shadow maps need to reach every caster between the camera and the light
+        let scene_corners: Vec<Vec3> = casters
+            .iter()
+            .flat_map(|draw| {
+                let (min, max) = draw.mesh.bounds();
+                (0..8).map(move |i| {
+                    let corner = glam::vec3(
+                        if i & 1 == 0 { min.x } else { max.x },
+                        if i & 2 == 0 { min.y } else { max.y },
+                        if i & 4 == 0 { min.z } else { max.z },
+                    );
+                    draw.model_matrix.transform_point3(corner)
+                })
+            })
+            .collect();
+
+        let resolution = self.shadows.resolution.max(1);
+        uniforms
+            .lights
+            .iter()
+            .map(|light| {
+                let mut shadow_map = light.shadow_map(
+                    self.view_matrix,
+                    self.projection_matrix,
+                    &scene_corners,
+                    &self.shadows,
+                    self.depth_convention,
+                )?;
+                for cascade in &mut shadow_map.cascades {
+                    let light_uniforms = Uniforms {
+                        view_matrix: cascade.view_matrix,
+                        projection_matrix: cascade.projection_matrix,
+                        camera_position: cascade.view_matrix.inverse().w_axis.truncate(),
+                        ..uniforms
+                    };
+                    let mut triangles = Vec::new();
+                    for draw in &casters {
+                        triangles.append(&mut self.process_mesh(
+                            draw.mesh,
+                            &draw.model_matrix,
+                            draw.material,
+                            draw.pipeline,
+                            &light_uniforms,
+                        ));
                     }
+                    let mut depth = self
+                        .shadow_depth_buffers
+                        .lock()
+                        .unwrap()
+                        .pop()
+                        .unwrap_or_default();
+                    depth.clear();
+                    depth.resize(resolution * resolution, self.depth_convention.far_depth());
+                    self.rasterize_depth(
+                        &triangles,
+                        resolution,
+                        resolution,
+                        &mut depth,
+                        &light_uniforms,
+                    );
+                    cascade.depth = depth;
+                }
+                Some(shadow_map)
+            })
+            .collect()
+    }
 
-                    // If two vertices are behind the near clipping plane, we should get one triangle back
-                    2 => {
-                        // Order vertices so that A is always the one in front of the near clipping plane
-                        let a;
-                        let b;
-                        let c;
-                        if v0.position.z > 0.0 {
-                            a = v0;
-                            b = v1;
-                            c = v2;
-                        } else if v1.position.z > 0.0 {
-                            a = v1;
-                            b = v2;
-                            c = v0;
-                        } else {
-                            // if v2.position.z > 0.0
-                            a = v2;
-                            b = v0;
-                            c = v1;
-                        }
+    // Hands the depth buffers of shadow maps that are no longer needed back to render_shadow_maps
+    fn recycle_shadow_maps(&self, shadow_maps: Vec<Option<ShadowMap>>) {
+        let mut buffers = self.shadow_depth_buffers.lock().unwrap();
+        for shadow_map in shadow_maps.into_iter().flatten() {
+            buffers.extend(shadow_map.cascades.into_iter().map(|cascade| cascade.depth));
+        }
+    }
 
-                        // Calculate mid_AC and mid_BC
-                        let t_mid_ab = (0.0 - a.position.z) / (b.position.z - a.position.z);
-                        let t_mid_ac = (0.0 - a.position.z) / (c.position.z - a.position.z);
-                        let mid_ab = a.lerp(b, t_mid_ab);
-                        let mid_ac = a.lerp(c, t_mid_ac);
+    // Renders shadow maps for the casters, then the draws in order with shadows. Returns how many
+    // triangles made it past clipping
+    pub(crate) fn draw_commands(
+        &self,
+        draws: &[&DrawCommand],
+        casters: &[&DrawCommand],
+        target: &mut RenderTarget,
+    ) -> usize {
+        let mut triangles_rasterized = 0;
+        if self.wireframe != WireframeMode::Only {
+            let shadow_maps = self.render_shadow_maps(casters);
+            let uniforms = Uniforms {
+                shadow_maps: &shadow_maps,
+                ..self.uniforms()
+            };
+            let mut triangles = Vec::new();
+            let mut blended_triangles = Vec::new();
+            for draw in draws {
+                let output = match draw.pipeline.blend {
+                    Some(_) => &mut blended_triangles,
+                    None => &mut triangles,
+                };
+                output.append(&mut self.process_mesh(
+                    draw.mesh,
+                    &draw.model_matrix,
+                    draw.material,
+                    draw.pipeline,
+                    &uniforms,
+                ));
+            }
+            // Sorting whole draws isn't enough, the loader merges every primitive with the same
+            // material into one mesh. So blended triangles are sorted furthest first on their own,
+            // by the depth of their centre
+            let far_depth = self.depth_convention.far_depth();
+            let nearness = |triangle: &TriangleQueueEntry| {
+                let depth =
+                    (triangle.v0.position.z + triangle.v1.position.z + triangle.v2.position.z)
+                        / 3.0;
+                (depth - far_depth).abs()
+            };
+            blended_triangles.sort_by(|a, b| nearness(a).total_cmp(&nearness(b)));
+            triangles.append(&mut blended_triangles);
+            triangles_rasterized = triangles.len();
+            self.rasterize(&triangles, target, &uniforms);
+            self.recycle_shadow_maps(shadow_maps);
+        }
+        triangles_rasterized
+    }
 
-                        // Return triangle
-                        new_triangles.push(a);
-                        new_triangles.push(mid_ab);
-                        new_triangles.push(mid_ac);
-                    }
+    // Draws the wireframes of the draws on top of what's already there, if enabled
+    pub(crate) fn draw_wireframes(&self, draws: &[&DrawCommand], target: &mut RenderTarget) {
+        if self.wireframe != WireframeMode::Off {
+            for draw in draws {
+                self.draw_mesh_wireframe(draw.mesh, &draw.model_matrix, draw.material, target);
+            }
+        }
+    }
 
-                    // Otherwise, don't render
-                    _ => {}
+    // Clips a triangle against the frustum in clip space, and pushes the resulting polygon as a
+    // triangle fan. Near and far are exact, the sides use the guard band, anything between the
+    // screen edges and the guard band is left to the bounding box clamp in the rasterizer.
+    fn clip_triangle(&self, v0: FragIn, v1: FragIn, v2: FragIn, new_triangles: &mut Vec<FragIn>) {
+        let guard_band = self.guard_band.max(1.0);
+
+        // Signed distance to each plane, positive is inside
+        let planes: [&dyn Fn(&Vec4) -> f32; 6] = [
+            &|p| p.z,                    // Depth 0, far with reversed-Z
+            &|p| p.w - p.z,              // Depth 1, near with reversed-Z
+            &|p| p.x + guard_band * p.w, // Left
+            &|p| guard_band * p.w - p.x, // Right
+            &|p| p.y + guard_band * p.w, // Bottom
+            &|p| guard_band * p.w - p.y, // Top
+        ];
+
+        // Most triangles are either fully inside or fully outside one of the planes
+        let mut fully_inside = true;
+        for plane in &planes {
+            let d0 = plane(&v0.position);
+            let d1 = plane(&v1.position);
+            let d2 = plane(&v2.position);
+            if d0 < 0.0 && d1 < 0.0 && d2 < 0.0 {
+                return;
+            }
+            fully_inside &= d0 >= 0.0 && d1 >= 0.0 && d2 >= 0.0;
+        }
+        if fully_inside {
+            new_triangles.push(v0);
+            new_triangles.push(v1);
+            new_triangles.push(v2);
+            return;
+        }
+
+        // Sutherland-Hodgman, one plane at a time. Attributes are interpolated linearly in clip
+        // space, so they stay correct after the perspective divide
+        let mut polygon = vec![v0, v1, v2];
+        let mut clipped = Vec::with_capacity(9);
+        for plane in &planes {
+            clipped.clear();
+            for i in 0..polygon.len() {
+                let a = polygon[i];
+                let b = polygon[(i + 1) % polygon.len()];
+                let distance_a = plane(&a.position);
+                let distance_b = plane(&b.position);
+                if distance_a >= 0.0 {
+                    clipped.push(a);
                 }
+                if (distance_a >= 0.0) != (distance_b >= 0.0) {
+                    let t = distance_a / (distance_a - distance_b);
+                    clipped.push(a.lerp(b, t));
+                }
+            }
+            std::mem::swap(&mut polygon, &mut clipped);
+            if polygon.len() < 3 {
+                return;
             }
+        }
+
+        // Triangle fan, which keeps the original winding
+        for i in 1..polygon.len() - 1 {
+            new_triangles.push(polygon[0]);
+            new_triangles.push(polygon[i]);
+            new_triangles.push(polygon[i + 1]);
+        }
+    }
+
+    // Runs the vertex shader and clipping for a whole mesh, giving back triangles ready to be binned
+    pub(crate) fn process_mesh<'a>(
+        &self,
+        mesh: &Mesh,
+        model_matrix: &Mat4,
+        material: Option<&'a Material>,
+        pipeline: PipelineState,
+        uniforms: &Uniforms,
+    ) -> Vec<TriangleQueueEntry<'a>> {
+        let uniforms = &uniforms.for_model(model_matrix);
+        let process_triangle = |verts: &[Vertex]| {
+            // Transform vertices
+            let v0 = self.vertex_shader.shade(&verts[0], model_matrix, uniforms);
+            let v1 = self.vertex_shader.shade(&verts[1], model_matrix, uniforms);
+            let v2 = self.vertex_shader.shade(&verts[2], model_matrix, uniforms);
+
+            // Create the vector for output triangles
+            let mut new_triangles = Vec::<FragIn>::new();
+            self.clip_triangle(v0, v1, v2, &mut new_triangles);
 
             // Perform perspective divide
             for item in &mut new_triangles {
@@ -449,47 +1219,64 @@ impl Renderer {
                 item.position.z /= item.position.w;
             }
 
-            // Draw vertices
-            for i in (0..new_triangles.len()).step_by(3) {
-                Self::draw_triangle_filled(
-                    new_triangles[i],
-                    new_triangles[i + 1],
-                    new_triangles[i + 2],
-                    colour_buffer,
-                    depth_buffer,
-                    width,
-                    height,
+            new_triangles
+                .chunks_exact(3)
+                .map(|triangle| TriangleQueueEntry {
+                    v0: triangle[0],
+                    v1: triangle[1],
+                    v2: triangle[2],
                     material,
-                );
-            }
+                    pipeline,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if self.multithreaded {
+            mesh.verts
+                .par_chunks_exact(3)
+                .flat_map_iter(process_triangle)
+                .collect()
+        } else {
+            mesh.verts
+                .chunks_exact(3)
+                .flat_map(process_triangle)
+                .collect()
         }
     }
 
-    pub fn draw_model(
+    pub fn draw_mesh(
         &self,
-        model: &Model,
+        mesh: &Mesh,
         model_matrix: &Transform,
-        colour_buffer: &mut [u32],
-        depth_buffer: &mut [f32],
-        width: usize,
-        height: usize,
+        target: &mut RenderTarget,
+        material: Option<&Material>,
     ) {
-        for (tex_id, mesh) in &model.meshes {
-            self.draw_mesh(
-                mesh,
-                model_matrix,
-                colour_buffer,
-                depth_buffer,
-                width,
-                height,
-                match tex_id.as_str() {
-                    "None" => None,
-                    _ => Some(&self.materials[tex_id]),
-                },
-            );
+        let draw = DrawCommand {
+            mesh,
+            model_matrix: model_matrix.trans_matrix(),
+            material,
+            pipeline: PipelineState::for_material(material),
+        };
+        self.draw_commands(&[&draw], &[&draw], target);
+        self.draw_wireframes(&[&draw], target);
+    }
+
+    // Materials are looked up by the name the loader registered them under
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        match name {
+            "None" => None,
+            _ => self.materials.get(name),
         }
     }
 
+    pub fn draw_model(&self, model: &Model, model_matrix: &Transform, target: &mut RenderTarget) {
+        // The queue bins the whole model at once, so every tile row has as much work as possible,
+        // and draws blended meshes after the opaque ones
+        let mut queue = RenderQueue::new();
+        queue.submit_model(self, model, model_matrix);
+        queue.flush(self, target);
+    }
+
     pub fn set_projection_matrix(&mut self, matrix: Mat4) {
         self.projection_matrix = matrix;
     }
@@ -497,3 +1284,448 @@ impl Renderer {
         self.view_matrix = matrix;
     }
 }
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::pipeline::{BlendState, ColourWriteMask, CompareFunction};
+
+    // Two triangles sharing the diagonal from the first to the third corner, in clip space
+    pub(crate) fn quad(corners: [Vec2; 4]) -> Mesh {
+        coloured_quad(corners, 0.5, Vec4::ONE)
+    }
+
+    pub(crate) fn coloured_quad(corners: [Vec2; 4], depth: f32, colour: Vec4) -> Mesh {
+        let vertex = |corner: Vec2| Vertex {
+            position: corner.extend(depth),
+            normal: Vec3::Z,
+            tangent: Vec4::ZERO,
+            colour,
+            uv: Vec2::ZERO,
+        };
+        Mesh {
+            verts: [0, 1, 2, 0, 2, 3]
+                .iter()
+                .map(|&i| vertex(corners[i]))
+                .collect(),
+        }
+    }
+
+    pub(crate) const SQUARE: [Vec2; 4] = [
+        Vec2::new(-0.5, -0.5),
+        Vec2::new(0.5, -0.5),
+        Vec2::new(0.5, 0.5),
+        Vec2::new(-0.5, 0.5),
+    ];
+
+    fn overdraw(corners: [Vec2; 4]) -> Vec<u32> {
+        let mut renderer = Renderer::new();
+        renderer.cull_mode = CullMode::None;
+        renderer.debug_view = DebugView::Overdraw;
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        renderer.draw_mesh(&quad(corners), &Transform::default(), &mut target, None);
+        target
+            .colour_buffer
+            .iter()
+            .map(|colour| colour >> 24)
+            .collect()
+    }
+
+    #[test]
+    fn targets_clear_to_the_far_plane_of_either_convention() {
+        for depth_convention in [DepthConvention::Standard, DepthConvention::ReversedZ] {
+            let mut renderer = Renderer::new();
+            renderer.depth_convention = depth_convention;
+            let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+            assert_eq!(target.clear_depth, depth_convention.far_depth());
+            let corners = [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)].map(Vec2::from);
+            renderer.draw_mesh(&quad(corners), &Transform::default(), &mut target, None);
+            assert_eq!(target.depth_at(32, 32), Some(0.5), "{depth_convention:?}");
+        }
+    }
+
+    // The test cube seen from above and to the side, lit by a sun that casts shadows
+    fn cube_scene() -> (Model, Renderer) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/test_cube.gltf");
+        let mut renderer = Renderer::new();
+        let model = Model::create_from_gltf(&path, &mut renderer).unwrap();
+        renderer.shadows.enabled = true;
+        renderer.lights.push(Light::directional(
+            glam::vec3(-1.0, -2.0, -0.5),
+            Vec3::ONE,
+            2.0,
+        ));
+        renderer.set_view_matrix(Mat4::look_at_rh(
+            glam::vec3(2.0, 1.5, 3.0),
+            Vec3::ZERO,
+            Vec3::Y,
+        ));
+        renderer.set_projection_matrix(renderer.depth_convention.perspective(
+            1.2,
+            203.0 / 117.0,
+            0.1,
+            Some(100.0),
+        ));
+        (model, renderer)
+    }
+
+    #[test]
+    fn multithreaded_matches_single_threaded() {
+        let (model, mut renderer) = cube_scene();
+        // Leaves partial tiles along the right and bottom edges
+        renderer.tile_size = 24;
+
+        let mut render = |multithreaded: bool| {
+            renderer.multithreaded = multithreaded;
+            let mut target = RenderTarget::new(203, 117, renderer.depth_convention);
+            renderer.draw_model(&model, &Transform::default(), &mut target);
+            target
+        };
+        let single = render(false);
+        let multi = render(true);
+        assert!(single.colour_buffer.iter().any(|&colour| colour != 0));
+        assert!(single.colour_buffer == multi.colour_buffer);
+        assert!(single.depth_buffer == multi.depth_buffer);
+    }
+
+    #[test]
+    fn shadow_maps_are_reused_across_frames() {
+        let (model, mut renderer) = cube_scene();
+        renderer.shadows.resolution = 64;
+        let pooled_buffers = |renderer: &Renderer| {
+            let buffers = renderer.shadow_depth_buffers.lock().unwrap();
+            let mut pointers: Vec<*const f32> =
+                buffers.iter().map(|depth| depth.as_ptr()).collect();
+            pointers.sort();
+            pointers
+        };
+
+        let mut frames = Vec::new();
+        let mut pools = Vec::new();
+        for _ in 0..2 {
+            let mut target = RenderTarget::new(203, 117, renderer.depth_convention);
+            renderer.draw_model(&model, &Transform::default(), &mut target);
+            frames.push(target.colour_buffer);
+            pools.push(pooled_buffers(&renderer));
+        }
+        assert_eq!(pools[0].len(), renderer.shadows.cascades);
+        assert_eq!(pools[0], pools[1]);
+        assert!(frames[0] == frames[1]);
+    }
+
+    #[test]
+    fn wireframe_lines_blend_in_linear_light() {
+        let renderer = Renderer::new();
+        let mut target = RenderTarget::new(2, 1, renderer.depth_convention);
+        target.colour_buffer[1] = 0x808080;
+        // Half coverage of white over black is linear 0.5, which is 188 in sRGB
+        renderer.plot_line_pixel(0, 0, 0.5, 0.5, &mut target);
+        assert_eq!(target.colour_buffer[0], 0xBCBCBC);
+        // Barely covered pixels keep their colour, rather than getting darker from truncation
+        renderer.plot_line_pixel(1, 0, 0.5, 1e-6, &mut target);
+        assert_eq!(target.colour_buffer[1], 0x808080);
+    }
+
+    #[test]
+    fn default_convention_matches_standard_projections() {
+        // Mat4::perspective_rh puts the near plane at depth 0, so the lower depth has to win
+        let renderer = Renderer::new();
+        let projection = Mat4::perspective_rh(1.0, 1.0, 1.0, 10.0);
+        assert!(
+            projection
+                .project_point3(glam::vec3(0.0, 0.0, -1.0))
+                .z
+                .abs()
+                < 1e-6
+        );
+        let near = coloured_quad(SQUARE, 0.25, glam::vec4(1.0, 0.0, 0.0, 1.0));
+        let far = coloured_quad(SQUARE, 0.75, glam::vec4(0.0, 1.0, 0.0, 1.0));
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        for mesh in [&near, &far] {
+            renderer.draw_mesh(mesh, &Transform::default(), &mut target, None);
+        }
+        assert_eq!(target.depth_at(32, 32), Some(0.25));
+    }
+
+    #[test]
+    fn blended_triangles_in_one_mesh_are_sorted() {
+        let mut renderer = Renderer::new();
+        renderer.depth_convention = DepthConvention::ReversedZ;
+        renderer.fragment_shader =
+            Box::new(|fragment: &Fragment, _: &Uniforms| Some(fragment.input.colour));
+        let material = Material {
+            alpha_mode: AlphaMode::Blend,
+            ..Default::default()
+        };
+        // Half transparent red in front of half transparent green, with reversed-Z
+        let near = coloured_quad(SQUARE, 0.75, glam::vec4(1.0, 0.0, 0.0, 0.5));
+        let far = coloured_quad(SQUARE, 0.25, glam::vec4(0.0, 1.0, 0.0, 0.5));
+        let expected = linear_to_srgb_rgb(glam::vec3(0.5, 0.25, 0.0));
+        for order in [[&near, &far], [&far, &near]] {
+            let mesh = Mesh {
+                verts: order.iter().flat_map(|mesh| mesh.verts.clone()).collect(),
+            };
+            let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+            renderer.draw_mesh(&mesh, &Transform::default(), &mut target, Some(&material));
+            let centre = target.index(32, 32).unwrap();
+            assert_eq!(target.colour_buffer[centre] & 0xFFFFFF, expected);
+        }
+    }
+
+    #[test]
+    fn depth_view_is_resolved_under_the_wireframe() {
+        let mut renderer = Renderer::new();
+        renderer.debug_view = DebugView::Depth;
+        renderer.wireframe = WireframeMode::Overlay;
+        renderer.wireframe_colour = Vec3::X;
+        let near = coloured_quad(SQUARE, 0.25, Vec4::ONE);
+        let far = coloured_quad(SQUARE.map(|corner| corner * 1.5), 0.75, Vec4::ONE);
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        let mut queue = RenderQueue::new();
+        for mesh in [&near, &far] {
+            queue.submit_mesh(mesh, &Transform::default(), None);
+        }
+        queue.flush(&renderer, &mut target);
+
+        let grey = target.depth_to_grey8();
+        for (x, y) in [(32, 24), (12, 32)] {
+            let index = target.index(x, y).unwrap();
+            let depth = grey[index];
+            assert_eq!(
+                target.colour_buffer[index] & 0xFFFFFF,
+                colour_rgb(depth, depth, depth)
+            );
+        }
+        assert_ne!(
+            grey[target.index(32, 32).unwrap()],
+            grey[target.index(12, 32).unwrap()]
+        );
+        assert!(target
+            .colour_buffer
+            .iter()
+            .any(|&colour| colour & 0xFFFFFF == 0xFF0000));
+    }
+
+    #[test]
+    fn cull_modes_and_double_sided_materials() {
+        let front = quad(SQUARE);
+        let back = Mesh {
+            verts: front.verts.iter().rev().copied().collect(),
+        };
+        let double_sided = Material {
+            double_sided: true,
+            ..Default::default()
+        };
+        // The blue channel of the normals view, the normal of back faces points away from the camera
+        let draw = |cull_mode, mesh: &Mesh, material: Option<&Material>| {
+            let mut renderer = Renderer::new();
+            renderer.cull_mode = cull_mode;
+            renderer.debug_view = DebugView::Normals;
+            let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+            renderer.draw_mesh(mesh, &Transform::default(), &mut target, material);
+            let centre = target.index(32, 32).unwrap();
+            (target.depth_buffer[centre] == 0.5).then_some(target.colour_buffer[centre] & 0xFF)
+        };
+
+        assert_eq!(draw(CullMode::Back, &front, None), Some(255));
+        assert_eq!(draw(CullMode::Back, &back, None), None);
+        assert_eq!(draw(CullMode::Front, &front, None), None);
+        assert_eq!(draw(CullMode::Front, &back, None), Some(0));
+        assert_eq!(draw(CullMode::None, &front, None), Some(255));
+        assert_eq!(draw(CullMode::None, &back, None), Some(0));
+        // Double-sided materials ignore the cull mode
+        assert_eq!(draw(CullMode::Back, &back, Some(&double_sided)), Some(0));
+        assert_eq!(
+            draw(CullMode::Front, &front, Some(&double_sided)),
+            Some(255)
+        );
+    }
+
+    #[test]
+    fn triangles_are_clipped_at_the_depth_planes() {
+        let renderer = Renderer::new();
+        // Depth goes from -0.5 on the left to 0.5 on the right, so the left half is clipped
+        let mut mesh = quad(SQUARE);
+        for vertex in &mut mesh.verts {
+            vertex.position.z = vertex.position.x;
+        }
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        renderer.draw_mesh(&mesh, &Transform::default(), &mut target, None);
+        assert_eq!(target.depth_at(20, 32), Some(target.clear_depth));
+        assert_eq!(target.depth_at(31, 32), Some(target.clear_depth));
+        let depth = target.depth_at(44, 32).unwrap();
+        assert!((depth - 0.39).abs() < 1.0 / 32.0, "{depth}");
+    }
+
+    #[test]
+    fn only_triangles_past_the_guard_band_are_clipped() {
+        let mut renderer = Renderer::new();
+        let triangles = |renderer: &Renderer, size: f32| {
+            let mesh = quad(SQUARE.map(|corner| corner * size));
+            let triangles = renderer.process_mesh(
+                &mesh,
+                &Mat4::IDENTITY,
+                None,
+                PipelineState::default(),
+                &renderer.uniforms(),
+            );
+            // Whatever the clipping did, the whole screen is covered
+            let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+            renderer.rasterize_triangles(&triangles, &mut target);
+            assert!(target
+                .depth_buffer
+                .iter()
+                .all(|&depth| (depth - 0.5).abs() < 1e-5));
+            triangles.len()
+        };
+        // Corners at 1.5 are inside the guard band of 2, corners at 20 are not
+        assert_eq!(triangles(&renderer, 3.0), 2);
+        assert!(triangles(&renderer, 40.0) > 2);
+        renderer.guard_band = 100.0;
+        assert_eq!(triangles(&renderer, 40.0), 2);
+    }
+
+    #[test]
+    fn custom_shaders_replace_the_default_ones() {
+        let mut renderer = Renderer::new();
+        renderer.ambient_light = Vec3::X;
+        // Moves everything a quarter of the screen to the right
+        renderer.vertex_shader = Box::new(
+            |vertex: &Vertex, model_matrix: &Mat4, uniforms: &Uniforms| {
+                let mut output = DefaultVertexShader.shade(vertex, model_matrix, uniforms);
+                output.position.x += 0.5 * output.position.w;
+                output
+            },
+        );
+        // The ambient light, discarding everything left of x = 40
+        renderer.fragment_shader = Box::new(|fragment: &Fragment, uniforms: &Uniforms| {
+            (fragment.input.position.x >= 40.0).then_some(uniforms.ambient_light.extend(1.0))
+        });
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        renderer.draw_mesh(&quad(SQUARE), &Transform::default(), &mut target, None);
+
+        // Moved out of the first, and discarded before writing depth in the second
+        for x in [20, 36] {
+            let index = target.index(x, 32).unwrap();
+            assert_eq!(target.colour_buffer[index] & 0xFFFFFF, 0);
+            assert_eq!(target.depth_buffer[index], target.clear_depth);
+        }
+        for x in [44, 60] {
+            let index = target.index(x, 32).unwrap();
+            assert_eq!(target.colour_buffer[index] & 0xFFFFFF, 0xFF0000);
+            assert_eq!(target.depth_buffer[index], 0.5);
+        }
+    }
+
+    #[test]
+    fn varyings_are_perspective_corrected() {
+        let mut renderer = Renderer::new();
+        // From 0 on the left edge to 1 on the right edge, where the quad is 4 times further away.
+        // Scaling the whole clip space position keeps it in the same place on screen
+        renderer.vertex_shader = Box::new(
+            |vertex: &Vertex, model_matrix: &Mat4, uniforms: &Uniforms| {
+                let mut output = DefaultVertexShader.shade(vertex, model_matrix, uniforms);
+                let t = vertex.position.x + 0.5;
+                output.position *= 1.0 + 3.0 * t;
+                output.varyings.set(3, t);
+                output
+            },
+        );
+        renderer.fragment_shader = Box::new(|fragment: &Fragment, _: &Uniforms| {
+            Some(glam::vec4(fragment.input.varyings.get(3), 0.0, 0.0, 1.0))
+        });
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        renderer.draw_mesh(&quad(SQUARE), &Transform::default(), &mut target, None);
+
+        // Halfway across the screen the near half covers 4 times as much as the far half, where
+        // interpolating linearly would give 0.5
+        let centre = target.index(32, 32).unwrap();
+        let value = srgb_rgb_to_linear(target.colour_buffer[centre]).x;
+        assert!((value - 0.2).abs() < 0.005, "{value}");
+    }
+
+    #[test]
+    fn pipeline_state_controls_depth_and_colour_writes() {
+        let mut renderer = Renderer::new();
+        renderer.fragment_shader =
+            Box::new(|fragment: &Fragment, _: &Uniforms| Some(fragment.input.colour));
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        let centre = target.index(32, 32).unwrap();
+        let mut draw = |depth, colour: Vec3, pipeline| {
+            let mesh = coloured_quad(SQUARE, depth, colour.extend(1.0));
+            let draw = DrawCommand {
+                mesh: &mesh,
+                model_matrix: Mat4::IDENTITY,
+                material: None,
+                pipeline,
+            };
+            renderer.draw_commands(&[&draw], &[], &mut target);
+            (
+                target.colour_buffer[centre] & 0xFFFFFF,
+                target.depth_buffer[centre],
+            )
+        };
+
+        assert_eq!(
+            draw(0.75, Vec3::X, PipelineState::default()),
+            (0xFF0000, 0.75)
+        );
+        // Fails the depth test, even though it is nearer
+        let greater = PipelineState {
+            depth_compare: Some(CompareFunction::Greater),
+            ..Default::default()
+        };
+        assert_eq!(draw(0.25, Vec3::Y, greater), (0xFF0000, 0.75));
+        // A depth pre-pass
+        let depth_only = PipelineState {
+            colour_write_mask: ColourWriteMask::NONE,
+            ..Default::default()
+        };
+        assert_eq!(draw(0.25, Vec3::Y, depth_only), (0xFF0000, 0.25));
+        // Only the blue channel, added to what is there
+        let equal_blue = PipelineState {
+            depth_compare: Some(CompareFunction::Equal),
+            depth_write: false,
+            colour_write_mask: ColourWriteMask {
+                red: false,
+                green: false,
+                blue: true,
+            },
+            blend: Some(BlendState::ADDITIVE),
+        };
+        assert_eq!(draw(0.25, Vec3::Z, equal_blue), (0xFF00FF, 0.25));
+        // Drawn without touching the depth buffer
+        let no_depth_write = PipelineState {
+            depth_write: false,
+            ..Default::default()
+        };
+        assert_eq!(draw(0.1, Vec3::ONE, no_depth_write), (0xFFFFFF, 0.25));
+    }
+
+    #[test]
+    fn shared_edges_are_drawn_once() {
+        // The diagonal goes through pixel centres, which both triangles touch
+        let corners = [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)];
+        let counts = overdraw(corners.map(Vec2::from));
+        assert_eq!(counts[20 + 20 * 64], 1);
+        assert!(counts.iter().all(|&count| count <= 1));
+
+        // The triangles see the diagonal from opposite ends, where rounding used to leave a gap.
+        // Pixel centres 22..=35 across and 29..=42 down are inside
+        let corners = [
+            (-0.3186, -0.3186),
+            (0.1102, -0.3186),
+            (0.1102, 0.1102),
+            (-0.3186, 0.1102),
+        ];
+        let counts = overdraw(corners.map(Vec2::from));
+        assert!(counts.iter().all(|&count| count <= 1));
+        assert!((29..=42).all(|y| (22..=35).all(|x| counts[x + y * 64] == 1)));
+    }
+}
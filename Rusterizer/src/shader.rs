@@ -0,0 +1,360 @@
+use glam::{Mat3, Mat4, Vec2, Vec3, Vec4};
+
+use crate::lighting::{blinn_phong, cook_torrance, Light};
+use crate::shadow::ShadowMap;
+use crate::structs::{FragIn, Varyings, Vertex};
+use crate::texture::{AlphaMode, Material, Sampler, Texture};
+
+// Values the renderer passes to every shader invocation in a frame. Shaders that need more than
+// this keep their own uniforms in their own fields.
+#[derive(Debug, Clone, Copy)]
+pub struct Uniforms<'a> {
+    pub view_matrix: Mat4,
+    pub projection_matrix: Mat4,
+    pub camera_position: Vec3,
+    pub lights: &'a [Light],
+    pub ambient_light: Vec3,
+    // One entry for every light, None for lights without shadows. Empty when nothing casts shadows
+    pub shadow_maps: &'a [Option<ShadowMap>],
+    // For the model matrix of the current draw, so they are worked out once per draw instead of
+    // once per vertex. See Uniforms::for_model
+    pub normal_matrix: Mat3,
+    // -1 when the model matrix mirrors, which flips which way the bitangent points
+    pub handedness: f32,
+}
+
+// The inverse transpose keeps normals perpendicular to the surface under non-uniform scaling
+pub fn normal_matrix(model_matrix: &Mat4) -> Mat3 {
+    Mat3::from_mat4(*model_matrix).inverse().transpose()
+}
+
+impl Uniforms<'_> {
+    pub fn for_model(&self, model_matrix: &Mat4) -> Self {
+        Uniforms {
+            normal_matrix: normal_matrix(model_matrix),
+            handedness: model_matrix.determinant().signum(),
+            ..*self
+        }
+    }
+
+    // How much of a light reaches a world space position, from 0 in full shadow to 1 fully lit
+    pub fn shadow(&self, light: usize, position: Vec3, normal: Vec3) -> f32 {
+        match self.shadow_maps.get(light) {
+            Some(Some(shadow_map)) => {
+                let view_distance = -self.view_matrix.transform_point3(position).z;
+                shadow_map.visibility(position, normal, view_distance)
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+// The default shaders pass the world space position to the fragment shader in these varyings
+pub const WORLD_POSITION_VARYING: usize = 0;
+
+// A pixel covered by a triangle, as seen by the fragment shader
+pub struct Fragment<'a> {
+    // Interpolated vertex shader outputs. The position is in screen space with the depth in z,
+    // everything else is perspective corrected
+    pub input: FragIn,
+    pub back_facing: bool,
+    pub material: Option<&'a Material>,
+    // Screen space derivatives of the texture coordinates, for filtering
+    pub duv_dx: Vec2,
+    pub duv_dy: Vec2,
+    // Overrides the max anisotropy of every sampler when set, see Renderer::max_anisotropy
+    pub max_anisotropy: Option<f32>,
+}
+
+impl Fragment<'_> {
+    // Samples the material's base colour texture at this pixel's texture coordinates, as RGBA in 0..1
+    pub fn sample_texture(&self) -> Option<Vec4> {
+        let material = self.material?;
+        Some(self.sample(&material.texture, &material.sampler))
+    }
+
+    // The normal to light this pixel with, from the normal map if the material has one. Back faces
+    // get their whole tangent frame flipped so they are lit like front faces
+    pub fn shading_normal(&self) -> Vec3 {
+        let flip = if self.back_facing { -1.0 } else { 1.0 };
+        let normal = self.input.normal.normalize_or_zero();
+        let Some(normal_texture) = self.material.and_then(|m| m.normal_texture.as_ref()) else {
+            return normal * flip;
+        };
+        // Without a usable tangent there is no way to orient the normal map
+        let tangent = self.input.tangent.truncate();
+        let tangent = (tangent - normal * normal.dot(tangent)).normalize_or_zero();
+        if tangent == Vec3::ZERO {
+            return normal * flip;
+        }
+        let bitangent = normal.cross(tangent) * self.input.tangent.w.signum();
+        let (normal, tangent, bitangent) = (normal * flip, tangent * flip, bitangent * flip);
+
+        let sample = self.sample(&normal_texture.texture, &normal_texture.sampler);
+        let scale = self.material.map_or(1.0, |material| material.normal_scale);
+        let mapped = sample.truncate() * 2.0 - 1.0;
+        (tangent * mapped.x * scale + bitangent * mapped.y * scale + normal * mapped.z)
+            .normalize_or_zero()
+    }
+
+    // Samples any texture at this pixel's texture coordinates, as RGBA in 0..1
+    pub fn sample(&self, texture: &Texture, sampler: &Sampler) -> Vec4 {
+        texture.sample(
+            self.input.uv,
+            self.duv_dx,
+            self.duv_dy,
+            self.max_anisotropy.unwrap_or(sampler.max_anisotropy),
+            sampler,
+        )
+    }
+}
+
+// Runs once for every vertex of every triangle, and should output a clip space position
+pub trait VertexShader: Send + Sync {
+    fn shade(&self, vertex: &Vertex, model_matrix: &Mat4, uniforms: &Uniforms) -> FragIn;
+}
+
+// Runs for every pixel that passes the depth test. Returns the RGBA colour of the pixel, or None
+// to discard it
+pub trait FragmentShader: Send + Sync {
+    fn shade(&self, fragment: &Fragment, uniforms: &Uniforms) -> Option<Vec4>;
+}
+
+impl<F> VertexShader for F
+where
+    F: Fn(&Vertex, &Mat4, &Uniforms) -> FragIn + Send + Sync,
+{
+    fn shade(&self, vertex: &Vertex, model_matrix: &Mat4, uniforms: &Uniforms) -> FragIn {
+        self(vertex, model_matrix, uniforms)
+    }
+}
+
+impl<F> FragmentShader for F
+where
+    F: Fn(&Fragment, &Uniforms) -> Option<Vec4> + Send + Sync,
+{
+    fn shade(&self, fragment: &Fragment, uniforms: &Uniforms) -> Option<Vec4> {
+        self(fragment, uniforms)
+    }
+}
+
+// Transforms the position to clip space, and the position and normal to world space
+pub struct DefaultVertexShader;
+
+impl VertexShader for DefaultVertexShader {
+    fn shade(&self, vertex: &Vertex, model_matrix: &Mat4, uniforms: &Uniforms) -> FragIn {
+        let world_position = model_matrix.mul_vec4(vertex.position.extend(1.0));
+        let position = uniforms.view_matrix.mul_vec4(world_position);
+        let position = uniforms.projection_matrix.mul_vec4(position);
+
+        let mut varyings = Varyings::default();
+        varyings.set_vec3(WORLD_POSITION_VARYING, world_position.truncate());
+        FragIn {
+            position,
+            normal: (uniforms.normal_matrix * vertex.normal).normalize_or_zero(),
+            tangent: (Mat3::from_mat4(*model_matrix) * vertex.tangent.truncate())
+                .extend(vertex.tangent.w * uniforms.handedness),
+            colour: vertex.colour,
+            uv: vertex.uv,
+            varyings,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadingModel {
+    BlinnPhong,
+    // Physically based, using the material's metallic and roughness parameters
+    CookTorrance,
+}
+
+// Lights the material's base colour, multiplied by the vertex colour, with the renderer's lights.
+// The alpha is handled as the material's alpha mode says, without a material it is opaque.
+pub struct DefaultFragmentShader {
+    pub shading_model: ShadingModel,
+    // Only used for Blinn-Phong
+    pub specular_strength: f32,
+    pub shininess: f32,
+}
+
+impl Default for DefaultFragmentShader {
+    fn default() -> Self {
+        DefaultFragmentShader {
+            shading_model: ShadingModel::CookTorrance,
+            specular_strength: 0.25,
+            shininess: 32.0,
+        }
+    }
+}
+
+impl FragmentShader for DefaultFragmentShader {
+    fn shade(&self, fragment: &Fragment, uniforms: &Uniforms) -> Option<Vec4> {
+        let mut albedo = fragment.input.colour;
+        let mut alpha = 1.0;
+        if let Some(material) = fragment.material {
+            albedo *= material.base_colour_factor * fragment.sample_texture()?;
+            match material.alpha_mode {
+                AlphaMode::Opaque => {}
+                AlphaMode::Mask if albedo.w < material.alpha_cutoff => return None,
+                AlphaMode::Mask => {}
+                AlphaMode::Blend => alpha = albedo.w,
+            }
+        }
+        let albedo = albedo.truncate();
+
+        let normal = fragment.shading_normal();
+        let position = fragment.input.varyings.vec3(WORLD_POSITION_VARYING);
+        let to_camera = (uniforms.camera_position - position).normalize_or_zero();
+
+        // Shadow lookups are offset along the surface's own normal, bumps from the normal map
+        // would only add noise
+        let mut surface_normal = fragment.input.normal.normalize_or_zero();
+        if fragment.back_facing {
+            surface_normal = -surface_normal;
+        }
+        let shadowing = |light| uniforms.shadow(light, position, surface_normal);
+
+        let colour = match self.shading_model {
+            ShadingModel::BlinnPhong => {
+                let (diffuse, specular) = blinn_phong(
+                    uniforms.lights,
+                    shadowing,
+                    uniforms.ambient_light,
+                    position,
+                    normal,
+                    to_camera,
+                    self.shininess,
+                );
+                albedo * diffuse + specular * self.specular_strength
+            }
+            ShadingModel::CookTorrance => {
+                // Without a material the surface is treated as a rough dielectric, so vertex
+                // coloured meshes look like they do with Blinn-Phong
+                let (mut metallic, mut roughness) = (0.0, 1.0);
+                if let Some(material) = fragment.material {
+                    metallic = material.metallic_factor;
+                    roughness = material.roughness_factor;
+                    if let Some(texture) = &material.metallic_roughness_texture {
+                        let sample = fragment.sample(&texture.texture, &texture.sampler);
+                        roughness *= sample.y;
+                        metallic *= sample.z;
+                    }
+                }
+                cook_torrance(
+                    uniforms.lights,
+                    shadowing,
+                    uniforms.ambient_light,
+                    position,
+                    normal,
+                    to_camera,
+                    albedo,
+                    metallic,
+                    roughness,
+                )
+            }
+        };
+        Some(colour.extend(alpha))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::MaterialTexture;
+
+    #[test]
+    fn back_faces_flip_the_whole_normal_map_frame() {
+        // Tilted towards the bitangent, (0, 0.6, 0.8) in tangent space
+        let material = Material {
+            normal_texture: Some(MaterialTexture::solid(glam::vec4(0.5, 0.8, 0.9, 1.0))),
+            ..Default::default()
+        };
+        let fragment = |back_facing| Fragment {
+            input: FragIn {
+                position: Vec4::ZERO,
+                normal: Vec3::Z,
+                tangent: glam::vec4(1.0, 0.0, 0.0, 1.0),
+                colour: Vec4::ONE,
+                uv: Vec2::ZERO,
+                varyings: Varyings::default(),
+            },
+            back_facing,
+            material: Some(&material),
+            duv_dx: Vec2::ZERO,
+            duv_dy: Vec2::ZERO,
+            max_anisotropy: None,
+        };
+        let front = fragment(false).shading_normal();
+        assert!(
+            front.abs_diff_eq(glam::vec3(0.0, 0.6, 0.8), 1e-5),
+            "{front}"
+        );
+        let back = fragment(true).shading_normal();
+        assert!(
+            back.abs_diff_eq(glam::vec3(0.0, -0.6, -0.8), 1e-5),
+            "{back}"
+        );
+    }
+
+    #[test]
+    fn default_vertex_shader_uses_the_normal_matrix_of_the_draw() {
+        let uniforms = Uniforms {
+            view_matrix: Mat4::IDENTITY,
+            projection_matrix: Mat4::IDENTITY,
+            camera_position: Vec3::ZERO,
+            lights: &[],
+            ambient_light: Vec3::ZERO,
+            shadow_maps: &[],
+            normal_matrix: Mat3::IDENTITY,
+            handedness: 1.0,
+        };
+        let vertex = Vertex {
+            position: Vec3::ZERO,
+            normal: glam::vec3(1.0, 1.0, 0.0).normalize(),
+            tangent: glam::vec4(1.0, -1.0, 0.0, 1.0),
+            colour: Vec4::ONE,
+            uv: Vec2::ZERO,
+        };
+        // Stretched along x and mirrored in y
+        let model_matrix = Mat4::from_scale(glam::vec3(2.0, -1.0, 1.0));
+        let output =
+            DefaultVertexShader.shade(&vertex, &model_matrix, &uniforms.for_model(&model_matrix));
+        assert!(output
+            .normal
+            .abs_diff_eq(glam::vec3(1.0, -2.0, 0.0).normalize(), 1e-6));
+        assert_eq!(output.normal.dot(output.tangent.truncate()), 0.0);
+        assert_eq!(output.tangent.w, -1.0);
+    }
+
+    #[test]
+    fn textures_are_filtered_with_their_own_sampler() {
+        // The base colour sampler has anisotropic filtering off, the stripes have it on
+        let material = Material::default();
+        let stripes = crate::texture::tests::stripes();
+        let mut fragment = Fragment {
+            input: FragIn {
+                position: Vec4::ZERO,
+                normal: Vec3::Z,
+                tangent: Vec4::ZERO,
+                colour: Vec4::ONE,
+                uv: Vec2::splat(0.5),
+                varyings: Varyings::default(),
+            },
+            back_facing: false,
+            material: Some(&material),
+            duv_dx: Vec2::X,
+            duv_dy: glam::vec2(0.0, 1e-6),
+            max_anisotropy: None,
+        };
+        assert_eq!(
+            fragment.sample(&stripes.texture, &stripes.sampler),
+            Vec4::ONE
+        );
+        // The renderer's override wins over the sampler
+        fragment.max_anisotropy = Some(1.0);
+        assert_eq!(
+            fragment.sample(&stripes.texture, &stripes.sampler),
+            Vec4::ZERO
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use glam::{Mat4, Vec3, Vec4Swizzles};
+
+use crate::depth::DepthConvention;
+use crate::lighting::{Light, LightKind};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowSettings {
+    // Off by default, the shadow maps are rendered again every frame, which can take longer than
+    // the rest of the frame
+    pub enabled: bool,
+    // Width and height of every shadow map, in texels
+    pub resolution: usize,
+    // How far surfaces are moved towards the light before looking them up, in world units. Too
+    // little gives shadow acne, too much makes shadows detach from their casters
+    pub depth_bias: f32,
+    // How far surfaces are moved along their normal before looking them up, in shadow map texels
+    pub normal_bias: f32,
+    // Shadows are filtered over (2 * radius + 1)^2 texels, 0 gives hard edges
+    pub pcf_radius: usize,
+    // Directional lights split the view frustum into this many shadow maps, each covering a range
+    // further away from the camera than the last
+    pub cascades: usize,
+    // How far away from the camera directional lights still cast shadows
+    pub max_distance: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            enabled: false,
+            resolution: 1024,
+            depth_bias: 0.02,
+            normal_bias: 1.0,
+            pcf_radius: 1,
+            cascades: 3,
+            max_distance: 50.0,
+        }
+    }
+}
+
+// The scene's depth as seen from a light, covering the view distances up to split_distance
+#[derive(Debug, Clone)]
+pub struct ShadowCascade {
+    pub view_matrix: Mat4,
+    pub projection_matrix: Mat4,
+    pub split_distance: f32,
+    // World size of a texel, at distance 1 from the light for perspective projections
+    pub texel_size: f32,
+    pub resolution: usize,
+    // Written by the rasterizer's depth-only pass, so it uses the same depth convention as the
+    // main depth buffer
+    pub depth: Vec<f32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShadowMap {
+    pub light: Light,
+    pub cascades: Vec<ShadowCascade>,
+    pub depth_bias: f32,
+    pub normal_bias: f32,
+    pub pcf_radius: usize,
+    // Has to match the renderer that fills in the cascades
+    pub depth_convention: DepthConvention,
+}
+
+impl ShadowMap {
+    // Fits one orthographic shadow map around each slice of the camera's view frustum. Casters
+    // outside the frustum but between it and the light are kept by stretching the depth range
+    // over the scene bounds
+    pub fn directional(
+        light: &Light,
+        direction: Vec3,
+        view_matrix: Mat4,
+        projection_matrix: Mat4,
+        scene_corners: &[Vec3],
+        settings: &ShadowSettings,
+        depth_convention: DepthConvention,
+    ) -> Self {
+        let inverse_view_projection = (projection_matrix * view_matrix).inverse();
+        let corners = |z: f32| {
+            [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+                .map(|(x, y)| inverse_view_projection.project_point3(glam::vec3(x, y, z)))
+        };
+        // The far plane can be at infinity, so the frustum's edges are followed from the near plane
+        // through a point halfway in depth instead
+        let far_depth = depth_convention.far_depth();
+        let near_corners = corners(1.0 - far_depth);
+        let middle_corners = corners(0.5);
+        let view_distance = |point: Vec3| -view_matrix.transform_point3(point).z;
+        let near = view_distance(near_corners[0]);
+        let middle = view_distance(middle_corners[0]);
+        let far = view_distance(corners(far_depth)[0]);
+        let max_distance = match far.is_finite() {
+            true => settings.max_distance.min(far),
+            false => settings.max_distance,
+        };
+
+        let light_view = Mat4::look_at_rh(Vec3::ZERO, direction, up_vector(direction));
+        let scene_z_max = scene_corners
+            .iter()
+            .map(|corner| light_view.transform_point3(*corner).z)
+            .fold(f32::NEG_INFINITY, f32::max);
+
+        // Halfway between uniform and logarithmic splits, so nearby cascades stay sharp without
+        // the far ones getting too stretched
+        let cascade_count = settings.cascades.max(1);
+        let mut cascades = Vec::with_capacity(cascade_count);
+        let mut start = near;
+        for i in 1..=cascade_count {
+            let t = i as f32 / cascade_count as f32;
+            let uniform = near + (max_distance - near) * t;
+            let logarithmic = near * (max_distance / near).powf(t);
+            let end = (uniform + logarithmic) * 0.5;
+
+            let slice: Vec<Vec3> = [start, end]
+                .iter()
+                .flat_map(|distance| {
+                    let t = (distance - near) / (middle - near);
+                    (0..4).map(move |i| near_corners[i].lerp(middle_corners[i], t))
+                })
+                .collect();
+
+            // A bounding sphere doesn't change size as the camera turns, which keeps the texel
+            // size constant, and snapping its centre to whole texels stops the edges crawling
+            let centre = slice.iter().sum::<Vec3>() / slice.len() as f32;
+            let radius = slice
+                .iter()
+                .map(|corner| corner.distance(centre))
+                .fold(0.0, f32::max);
+            let radius = (radius * 16.0).ceil() / 16.0;
+            let texel_size = radius * 2.0 / settings.resolution as f32;
+            let centre = light_view.transform_point3(centre);
+            let centre_x = (centre.x / texel_size).floor() * texel_size;
+            let centre_y = (centre.y / texel_size).floor() * texel_size;
+
+            // Same depth convention as the camera's depth buffer
+            let near_plane = -(centre.z + radius).max(scene_z_max);
+            let far_plane = -(centre.z - radius);
+            let projection_matrix = depth_convention.orthographic(
+                centre_x - radius,
+                centre_x + radius,
+                centre_y - radius,
+                centre_y + radius,
+                near_plane,
+                far_plane,
+            );
+
+            cascades.push(ShadowCascade {
+                view_matrix: light_view,
+                projection_matrix,
+                split_distance: end,
+                texel_size,
+                resolution: settings.resolution,
+                depth: Vec::new(),
+            });
+            start = end;
+        }
+
+        ShadowMap::new(light, cascades, settings, depth_convention)
+    }
+
+    // A single perspective shadow map covering the light's outer cone
+    pub fn spot(
+        light: &Light,
+        position: Vec3,
+        direction: Vec3,
+        outer_cone_angle: f32,
+        scene_corners: &[Vec3],
+        settings: &ShadowSettings,
+        depth_convention: DepthConvention,
+    ) -> Self {
+        let far = light.range.unwrap_or_else(|| {
+            scene_corners
+                .iter()
+                .map(|corner| corner.distance(position))
+                .fold(0.0, f32::max)
+        });
+        let far = far.max(0.01);
+        let fov = (outer_cone_angle * 2.0).clamp(0.01, 170.0_f32.to_radians());
+
+        let cascade = ShadowCascade {
+            view_matrix: Mat4::look_at_rh(position, position + direction, up_vector(direction)),
+            projection_matrix: depth_convention.perspective(fov, 1.0, far * 0.001, Some(far)),
+            split_distance: f32::INFINITY,
+            texel_size: (fov * 0.5).tan() * 2.0 / settings.resolution as f32,
+            resolution: settings.resolution,
+            depth: Vec::new(),
+        };
+        ShadowMap::new(light, vec![cascade], settings, depth_convention)
+    }
+
+    fn new(
+        light: &Light,
+        cascades: Vec<ShadowCascade>,
+        settings: &ShadowSettings,
+        depth_convention: DepthConvention,
+    ) -> Self {
+        ShadowMap {
+            light: *light,
+            cascades,
+            depth_bias: settings.depth_bias,
+            normal_bias: settings.normal_bias,
+            pcf_radius: settings.pcf_radius,
+            depth_convention,
+        }
+    }
+
+    // How much of the light reaches a surface, from 0 in full shadow to 1 fully lit. Anything
+    // outside the shadow maps is lit
+    pub fn visibility(&self, position: Vec3, normal: Vec3, view_distance: f32) -> f32 {
+        let Some(cascade) = self
+            .cascades
+            .iter()
+            .find(|cascade| view_distance <= cascade.split_distance)
+        else {
+            return 1.0;
+        };
+        if cascade.depth.is_empty() {
+            return 1.0;
+        }
+
+        // For perspective projections w is the distance from the light, which is how much
+        // bigger texels get. For orthographic ones it is 1
+        let view_projection = cascade.projection_matrix * cascade.view_matrix;
+        let w = (view_projection * position.extend(1.0)).w;
+        let (to_light, _) = self.light.incoming(position);
+        let position = position
+            + normal * (self.normal_bias * cascade.texel_size * w)
+            + to_light * self.depth_bias;
+
+        // The same depth the rasterizer writes for this position
+        let clip = view_projection * position.extend(1.0);
+        let ndc = clip.xyz() / clip.w;
+        let depth = ndc.z;
+        let resolution = cascade.resolution as f32;
+        let x = (ndc.x + 1.0) / 2.0 * resolution;
+        let y = (-ndc.y + 1.0) / 2.0 * resolution;
+        if !(0.0..resolution).contains(&x) || !(0.0..resolution).contains(&y) {
+            return 1.0;
+        }
+
+        let lit = |x: i32, y: i32| {
+            if x < 0 || y < 0 || x >= cascade.resolution as i32 || y >= cascade.resolution as i32 {
+                return 1.0;
+            }
+            let stored = cascade.depth[x as usize + y as usize * cascade.resolution];
+            match self
+                .depth_convention
+                .nearer_or_equal()
+                .passes(depth, stored)
+            {
+                true => 1.0,
+                false => 0.0,
+            }
+        };
+
+        // Every tap compares against its four nearest texels and blends the results, so shadow
+        // edges move smoothly instead of in whole texels
+        let (base_x, base_y) = (x.floor(), y.floor());
+        let (fraction_x, fraction_y) = (x - base_x, y - base_y);
+        let radius = self.pcf_radius as i32;
+        let mut total = 0.0;
+        for offset_y in -radius..=radius {
+            for offset_x in -radius..=radius {
+                let x = base_x as i32 + offset_x;
+                let y = base_y as i32 + offset_y;
+                let top = lit(x, y) + (lit(x + 1, y) - lit(x, y)) * fraction_x;
+                let bottom = lit(x, y + 1) + (lit(x + 1, y + 1) - lit(x, y + 1)) * fraction_x;
+                total += top + (bottom - top) * fraction_y;
+            }
+        }
+        total / ((radius * 2 + 1) * (radius * 2 + 1)) as f32
+    }
+}
+
+impl Light {
+    // Builds the shadow maps for this light, without rendering them yet. Point lights don't cast
+    // shadows
+    pub fn shadow_map(
+        &self,
+        view_matrix: Mat4,
+        projection_matrix: Mat4,
+        scene_corners: &[Vec3],
+        settings: &ShadowSettings,
+        depth_convention: DepthConvention,
+    ) -> Option<ShadowMap> {
+        if !self.cast_shadows {
+            return None;
+        }
+        match self.kind {
+            LightKind::Directional { direction } => Some(ShadowMap::directional(
+                self,
+                direction,
+                view_matrix,
+                projection_matrix,
+                scene_corners,
+                settings,
+                depth_convention,
+            )),
+            LightKind::Spot {
+                position,
+                direction,
+                outer_cone_angle,
+                ..
+            } => Some(ShadowMap::spot(
+                self,
+                position,
+                direction,
+                outer_cone_angle,
+                scene_corners,
+                settings,
+                depth_convention,
+            )),
+            LightKind::Point { .. } => None,
+        }
+    }
+}
+
+// Any up vector works for a light's view, as long as it isn't parallel to the view direction
+fn up_vector(direction: Vec3) -> Vec3 {
+    match direction.normalize_or_zero().y.abs() > 0.99 {
+        true => Vec3::X,
+        false => Vec3::Y,
+    }
+}
@@ -1,11 +1,17 @@
+use std::ops::{Add, Mul};
+
 use glam::{Mat4, Quat, Vec2, Vec3, Vec4};
 
+// How many extra f32 attributes a vertex shader can pass on to the fragment shader
+pub const MAX_VARYINGS: usize = 16;
+
 #[derive(Debug, Copy, Clone)]
 pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
-    pub tangent: Vec3,
-    pub colour: Vec3,
+    // The sign of w says which way the bitangent points, cross(normal, tangent) * w
+    pub tangent: Vec4,
+    pub colour: Vec4,
     pub uv: Vec2,
 }
 
@@ -13,11 +19,18 @@ pub struct Vertex {
 pub struct FragIn {
     pub position: Vec4,
     pub normal: Vec3,
-    pub tangent: Vec3,
-    pub colour: Vec3,
+    // The sign of w says which way the bitangent points, cross(normal, tangent) * w
+    pub tangent: Vec4,
+    pub colour: Vec4,
     pub uv: Vec2,
+    pub varyings: Varyings,
 }
 
+// Extra attributes written by the vertex shader, which get clipped, perspective corrected and
+// interpolated like the built in ones. Which slot holds what is up to the shaders.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Varyings(pub [f32; MAX_VARYINGS]);
+
 pub struct Transform {
     pub translation: Vec3,
     pub rotation: Quat,
@@ -32,6 +45,97 @@ impl FragIn {
             tangent: self.tangent.lerp(rhs.tangent, t),
             colour: self.colour.lerp(rhs.colour, t),
             uv: self.uv.lerp(rhs.uv, t),
+            varyings: self.varyings.lerp(rhs.varyings, t),
+        }
+    }
+}
+
+impl Add for FragIn {
+    type Output = FragIn;
+
+    fn add(self, rhs: FragIn) -> FragIn {
+        FragIn {
+            position: self.position + rhs.position,
+            normal: self.normal + rhs.normal,
+            tangent: self.tangent + rhs.tangent,
+            colour: self.colour + rhs.colour,
+            uv: self.uv + rhs.uv,
+            varyings: self.varyings + rhs.varyings,
+        }
+    }
+}
+
+impl Mul<f32> for FragIn {
+    type Output = FragIn;
+
+    fn mul(self, rhs: f32) -> FragIn {
+        FragIn {
+            position: self.position * rhs,
+            normal: self.normal * rhs,
+            tangent: self.tangent * rhs,
+            colour: self.colour * rhs,
+            uv: self.uv * rhs,
+            varyings: self.varyings * rhs,
+        }
+    }
+}
+
+impl Varyings {
+    pub fn get(&self, slot: usize) -> f32 {
+        self.0[slot]
+    }
+    pub fn vec2(&self, slot: usize) -> Vec2 {
+        Vec2::from_slice(&self.0[slot..])
+    }
+    pub fn vec3(&self, slot: usize) -> Vec3 {
+        Vec3::from_slice(&self.0[slot..])
+    }
+    pub fn vec4(&self, slot: usize) -> Vec4 {
+        Vec4::from_slice(&self.0[slot..])
+    }
+
+    pub fn set(&mut self, slot: usize, value: f32) {
+        self.0[slot] = value;
+    }
+    pub fn set_vec2(&mut self, slot: usize, value: Vec2) {
+        value.write_to_slice(&mut self.0[slot..]);
+    }
+    pub fn set_vec3(&mut self, slot: usize, value: Vec3) {
+        value.write_to_slice(&mut self.0[slot..]);
+    }
+    pub fn set_vec4(&mut self, slot: usize, value: Vec4) {
+        value.write_to_slice(&mut self.0[slot..]);
+    }
+
+    pub fn lerp(&self, rhs: Varyings, t: f32) -> Varyings {
+        Varyings(std::array::from_fn(|i| {
+            self.0[i] + (rhs.0[i] - self.0[i]) * t
+        }))
+    }
+}
+
+impl Add for Varyings {
+    type Output = Varyings;
+
+    fn add(self, rhs: Varyings) -> Varyings {
+        Varyings(std::array::from_fn(|i| self.0[i] + rhs.0[i]))
+    }
+}
+
+impl Mul<f32> for Varyings {
+    type Output = Varyings;
+
+    fn mul(self, rhs: f32) -> Varyings {
+        Varyings(self.0.map(|value| value * rhs))
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
         }
     }
 }
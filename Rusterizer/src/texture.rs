@@ -1,14 +1,28 @@
 use crate::helpers::*;
+use glam::{Vec2, Vec4};
 use std::path::Path;
 
+// Anisotropic filtering never takes more probes than this, however the sampler is set up
+pub const MAX_ANISOTROPY: f32 = 16.0;
+
 pub struct Texture {
     pub width: usize,
     pub height: usize,
     pub depth: usize,
-    pub data: Vec<u32>,
+    // Linear RGBA in 0..1, sRGB images are decoded when they are loaded so filtering and mipmaps
+    // average light instead of gamma encoded values
+    pub data: Vec<Vec4>,
     pub mipmap_offsets: Vec<usize>,
 }
 
+// How the colour channels of an image are encoded. glTF stores base colours as sRGB, and data like
+// normals, metallic and roughness as linear values. Alpha is always linear
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColourSpace {
+    Linear,
+    Srgb,
+}
+
 #[derive(PartialEq)]
 pub enum FilterMode {
     Point,
@@ -28,11 +42,97 @@ pub struct Sampler {
     pub wrap_mode_s: WrapMode,
     pub wrap_mode_t: WrapMode,
     pub mipmap_enabled: bool,
+    // Added to the computed level of detail, positive values make the texture blurrier
+    pub lod_bias: f32,
+    pub min_lod: f32,
+    pub max_lod: f32,
+    // Up to this many probes are taken along the longest axis of a pixel's footprint, clamped to
+    // 1.0..=MAX_ANISOTROPY. 1.0 disables anisotropic filtering
+    pub max_anisotropy: f32,
+}
+
+pub struct MaterialTexture {
+    pub texture: Texture,
+    pub sampler: Sampler,
+}
+
+impl MaterialTexture {
+    // A single texel of one colour, with a point sampler. Materials without a base colour texture
+    // get a white one
+    pub fn solid(colour: Vec4) -> Self {
+        MaterialTexture {
+            texture: Texture {
+                width: 1,
+                height: 1,
+                depth: 1,
+                data: vec![colour; 1],
+                mipmap_offsets: vec![0usize; 1],
+            },
+            sampler: Sampler {
+                filter_mode_mag: FilterMode::Point,
+                filter_mode_min: FilterMode::Point,
+                filter_mode_mipmap: FilterMode::Point,
+                wrap_mode_s: WrapMode::Clamp,
+                wrap_mode_t: WrapMode::Clamp,
+                mipmap_enabled: false,
+                lod_bias: 0.0,
+                min_lod: 0.0,
+                max_lod: 1000.0,
+                max_anisotropy: 1.0,
+            },
+        }
+    }
+}
+
+// How the alpha of a material's base colour is used, as in glTF
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaMode {
+    // Alpha is ignored, the surface is fully opaque
+    Opaque,
+    // Pixels with an alpha below the material's cutoff are discarded, the rest are opaque
+    Mask,
+    // Blended over what is behind it, without writing depth
+    Blend,
 }
 
+// glTF's metallic-roughness material. The texture and sampler are for the base colour
 pub struct Material {
     pub texture: Texture,
     pub sampler: Sampler,
+    // Double sided materials ignore the renderer's cull mode
+    pub double_sided: bool,
+    // Multiplied with the base colour texture, in RGBA
+    pub base_colour_factor: Vec4,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    // Roughness in the green channel and metallic in the blue channel, multiplied with the factors
+    pub metallic_roughness_texture: Option<MaterialTexture>,
+    // Tangent space normals, the x and y of which get multiplied by the scale
+    pub normal_texture: Option<MaterialTexture>,
+    pub normal_scale: f32,
+    pub alpha_mode: AlphaMode,
+    // Only used in AlphaMode::Mask
+    pub alpha_cutoff: f32,
+}
+
+// The glTF default material, a white rough metal
+impl Default for Material {
+    fn default() -> Self {
+        let base_colour = MaterialTexture::solid(Vec4::ONE);
+        Material {
+            texture: base_colour.texture,
+            sampler: base_colour.sampler,
+            double_sided: false,
+            base_colour_factor: Vec4::ONE,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -45,20 +145,23 @@ enum PixelComp {
 }
 
 impl Texture {
-    pub fn load(path: &Path) -> Self {
+    pub fn load(path: &Path, colour_space: ColourSpace) -> Self {
         //Load image
         let loaded_image = stb_image::image::load(path);
 
-        //Map the image data to argb8 format
+        //Map the image data to linear RGBA
         if let stb_image::image::LoadResult::ImageU8(image) = loaded_image {
             if image.depth == 4 {
                 let data = (0..image.data.len() / 4)
                     .map(|id| {
-                        colour_rgba(
-                            image.data[id * 4 + 3],
-                            image.data[id * 4],
-                            image.data[id * 4 + 1],
-                            image.data[id * 4 + 2],
+                        decode_texel(
+                            [
+                                image.data[id * 4],
+                                image.data[id * 4 + 1],
+                                image.data[id * 4 + 2],
+                                image.data[id * 4 + 3],
+                            ],
+                            colour_space,
                         )
                     })
                     .collect();
@@ -72,11 +175,14 @@ impl Texture {
             } else if image.depth == 3 {
                 let data = (0..image.data.len() / 3)
                     .map(|id| {
-                        colour_rgba(
-                            255,
-                            image.data[id * 3],
-                            image.data[id * 3 + 1],
-                            image.data[id * 3 + 2],
+                        decode_texel(
+                            [
+                                image.data[id * 3],
+                                image.data[id * 3 + 1],
+                                image.data[id * 3 + 2],
+                                255,
+                            ],
+                            colour_space,
                         )
                     })
                     .collect();
@@ -95,79 +201,146 @@ impl Texture {
         }
     }
 
-    //Get ARGB value from a UV coordinate
-    pub fn argb_at_uv(
+    // Picks the mip level from the screen space derivatives of the texture coordinates, using the
+    // axis along which the texture is squeezed the most. Also returns whether the texture is
+    // magnified, which is decided before the sampler's clamps are applied.
+    pub fn mip_level(&self, sampler: &Sampler, duv_dx: Vec2, duv_dy: Vec2) -> (f32, bool) {
+        let texture_size = glam::vec2(self.width as f32, self.height as f32);
+        let length_dx = (duv_dx * texture_size).length_squared();
+        let length_dy = (duv_dy * texture_size).length_squared();
+        let lod = 0.5 * length_dx.max(length_dy).log2() + sampler.lod_bias;
+        let is_mag = lod <= 0.0;
+
+        if !sampler.mipmap_enabled || lod.is_nan() {
+            return (0.0, is_mag);
+        }
+        let max_level = self.mipmap_offsets.len().saturating_sub(1) as f32;
+        // Not clamp, the sampler's range can be empty when min_lod is set above max_lod
+        let mip_level = lod
+            .max(sampler.min_lod)
+            .min(sampler.max_lod)
+            .clamp(0.0, max_level);
+        (mip_level, is_mag)
+    }
+
+    // Samples the texture for a pixel with the given uv derivatives. When the pixel's footprint is
+    // stretched, several probes are spread along its longest axis, so the mip level only has to
+    // fit the shortest axis instead of blurring the whole footprint.
+    pub fn sample(
+        &self,
+        uv: Vec2,
+        duv_dx: Vec2,
+        duv_dy: Vec2,
+        max_anisotropy: f32,
+        sampler: &Sampler,
+    ) -> Vec4 {
+        let texture_size = glam::vec2(self.width as f32, self.height as f32);
+        let length_dx = (duv_dx * texture_size).length();
+        let length_dy = (duv_dy * texture_size).length();
+        let (major_axis, minor_axis, major_length, minor_length) = match length_dx >= length_dy {
+            true => (duv_dx, duv_dy, length_dx, length_dy),
+            false => (duv_dy, duv_dx, length_dy, length_dx),
+        };
+        let max_anisotropy = match max_anisotropy.is_nan() {
+            true => 1.0,
+            false => max_anisotropy.clamp(1.0, MAX_ANISOTROPY),
+        };
+        let probes = match major_length > 0.0 {
+            true => (major_length / minor_length.max(f32::EPSILON))
+                .min(max_anisotropy)
+                .ceil()
+                .max(1.0) as usize,
+            false => 1,
+        };
+
+        // Each probe covers an equal part of the major axis
+        let (mip_level, is_mag) = self.mip_level(sampler, major_axis / probes as f32, minor_axis);
+        if probes == 1 {
+            return self.rgba_at_uv(uv.x, uv.y, mip_level, is_mag, sampler);
+        }
+        let mut sum = Vec4::ZERO;
+        for i in 0..probes {
+            let probe = uv + major_axis * ((i as f32 + 0.5) / probes as f32 - 0.5);
+            sum += self.rgba_at_uv(probe.x, probe.y, mip_level, is_mag, sampler);
+        }
+        sum / probes as f32
+    }
+
+    //Get RGBA value from a UV coordinate
+    pub fn rgba_at_uv(
         &self,
         u: f32,
         v: f32,
-        mip_level: usize,
+        mip_level: f32,
         is_mag: bool,
-        material: &Material,
-    ) -> u32 {
-        let u = match material.sampler.wrap_mode_s {
+        sampler: &Sampler,
+    ) -> Vec4 {
+        let u = match sampler.wrap_mode_s {
             WrapMode::Repeat => u - u.floor(), // Repeat - like a saw wave
             WrapMode::Mirror => 2.0 * (u * 0.5 - (u * 0.5 + 0.5).floor()).abs(), // Mirror - like a triangle wave
             WrapMode::Clamp => u.clamp(0.0, 1.0 - f32::EPSILON),
         };
-        let v = match material.sampler.wrap_mode_s {
+        let v = match sampler.wrap_mode_t {
             WrapMode::Repeat => v - v.floor(), // Repeat - like a saw wave
             WrapMode::Mirror => 2.0 * (v * 0.5 - (v * 0.5 + 0.5).floor()).abs(), // Mirror - like a triangle wave
             WrapMode::Clamp => v.clamp(0.0, 1.0 - f32::EPSILON),
         };
 
-        let (u, v) = (
-            u * (self.width >> mip_level) as f32,
-            v * (self.height >> mip_level) as f32,
-        );
+        let filter_mode = match is_mag {
+            true => &sampler.filter_mode_mag,
+            false => &sampler.filter_mode_min,
+        };
 
-        if (is_mag && material.sampler.filter_mode_mag == FilterMode::Linear)
-            || (!is_mag && material.sampler.filter_mode_min == FilterMode::Linear)
+        // Trilinear filtering blends between the two nearest mip levels
+        let level_below = mip_level.floor() as usize;
+        let blend = mip_level - mip_level.floor();
+        if sampler.filter_mode_mipmap == FilterMode::Linear
+            && blend > 0.0
+            && level_below + 1 < self.mipmap_offsets.len()
         {
-            // Find weights
-            let u1 = u.floor();
-            let u2 = u.ceil();
-            let v1 = v.floor();
-            let v2 = v.ceil();
-            let weight4 = ((u - u1) * (v - v1)) / ((u2 - u1) * (v2 - v1));
-            let weight3 = ((u2 - u) * (v - v1)) / ((u2 - u1) * (v2 - v1));
-            let weight2 = ((u - u1) * (v2 - v)) / ((u2 - u1) * (v2 - v1));
-            let weight1 = ((u2 - u) * (v2 - v)) / ((u2 - u1) * (v2 - v1));
-
-            // Sample texture
-            let index1 = coords_to_index(u1 as usize, v1 as usize, self.width >> mip_level);
-            let index2 = coords_to_index(
-                (u2 as usize) % (self.width >> mip_level),
-                v1 as usize,
-                self.width >> mip_level,
-            );
-            let index3 = coords_to_index(
-                u1 as usize,
-                (v2 as usize) % (self.height >> mip_level),
-                self.width >> mip_level,
-            );
-            let index4 = coords_to_index(
-                (u2 as usize) % (self.width >> mip_level),
-                (v2 as usize) % (self.height >> mip_level),
-                self.width >> mip_level,
-            );
+            let sample_below = self.rgba_at_level(u, v, level_below, filter_mode);
+            let sample_above = self.rgba_at_level(u, v, level_below + 1, filter_mode);
+            sample_below.lerp(sample_above, blend)
+        } else {
+            self.rgba_at_level(u, v, mip_level.round() as usize, filter_mode)
+        }
+    }
+
+    // Sample a single mip level, with UV coordinates that are already wrapped to 0..1
+    fn rgba_at_level(&self, u: f32, v: f32, mip_level: usize, filter_mode: &FilterMode) -> Vec4 {
+        let width = self.width >> mip_level;
+        let height = self.height >> mip_level;
+        let (u, v) = (u * width as f32, v * height as f32);
+        let texel = |x: usize, y: usize| {
+            self.data
+                [self.mipmap_offsets[mip_level] + coords_to_index(x % width, y % height, width)]
+        };
+
+        if *filter_mode == FilterMode::Linear {
+            // Find weights, from how far the point is past the texel to its top left
+            let (x, y) = (u.floor() as usize, v.floor() as usize);
+            let (fraction_u, fraction_v) = (u.fract(), v.fract());
 
+            // Sample texture, the neighbours on the right and bottom edges wrap around
             average_four_pixels(
-                self.data[self.mipmap_offsets[mip_level] + index1],
-                self.data[self.mipmap_offsets[mip_level] + index2],
-                self.data[self.mipmap_offsets[mip_level] + index3],
-                self.data[self.mipmap_offsets[mip_level] + index4],
-                weight1,
-                weight2,
-                weight3,
-                weight4,
+                texel(x, y),
+                texel(x + 1, y),
+                texel(x, y + 1),
+                texel(x + 1, y + 1),
+                (1.0 - fraction_u) * (1.0 - fraction_v),
+                fraction_u * (1.0 - fraction_v),
+                (1.0 - fraction_u) * fraction_v,
+                fraction_u * fraction_v,
             )
         } else {
-            let index = coords_to_index(u as usize, v as usize, self.width >> mip_level);
-            self.data[self.mipmap_offsets[mip_level] + index]
+            texel(u as usize, v as usize)
         }
     }
 
-    pub fn load_texture_from_gltf_image(image: &gltf::image::Data) -> Texture {
+    pub fn load_texture_from_gltf_image(
+        image: &gltf::image::Data,
+        colour_space: ColourSpace,
+    ) -> Texture {
         // Get pixel swizzle pattern
         let swizzle_pattern = match image.format {
             gltf::image::Format::R8 => vec![PixelComp::Red],
@@ -222,30 +395,19 @@ impl Texture {
             height: image.height as usize,
             depth: 4,
             data: {
-                let mut data = Vec::<u32>::new();
+                let mut data = Vec::<Vec4>::new();
                 for i in (0..image.pixels.len()).step_by(swizzle_pattern.len()) {
-                    let mut new_pixel = 0xFFFFFFFFu32;
+                    let mut new_pixel = [255u8; 4];
                     for (comp, entry) in swizzle_pattern.iter().enumerate() {
                         match entry {
                             PixelComp::Skip => {}
-                            PixelComp::Red => {
-                                new_pixel = new_pixel & 0xFFFFFF00 | image.pixels[i + comp] as u32
-                            }
-                            PixelComp::Green => {
-                                new_pixel =
-                                    new_pixel & 0xFFFF00FF | (image.pixels[i + comp] as u32) << 8
-                            }
-                            PixelComp::Blue => {
-                                new_pixel =
-                                    new_pixel & 0xFF00FFFF | (image.pixels[i + comp] as u32) << 16
-                            }
-                            PixelComp::Alpha => {
-                                new_pixel =
-                                    new_pixel & 0x00FFFFFF | (image.pixels[i + comp] as u32) << 24
-                            }
+                            PixelComp::Red => new_pixel[0] = image.pixels[i + comp],
+                            PixelComp::Green => new_pixel[1] = image.pixels[i + comp],
+                            PixelComp::Blue => new_pixel[2] = image.pixels[i + comp],
+                            PixelComp::Alpha => new_pixel[3] = image.pixels[i + comp],
                         }
                     }
-                    data.push(new_pixel);
+                    data.push(decode_texel(new_pixel, colour_space));
                 }
                 data
             },
@@ -271,7 +433,7 @@ impl Texture {
             }
 
             // Create new texture vector for simplicity sake
-            let mut new_mipmap = Vec::<u32>::new();
+            let mut new_mipmap = Vec::<Vec4>::new();
             for y in 0..dst_height {
                 for x in 0..dst_width {
                     // Sample 4 pixels from the source and combine them into one
@@ -283,16 +445,8 @@ impl Texture {
                         self.data[src_offset + ((x * 2) + 0) + ((y * 2) + 1) * src_width];
                     let pixel_sample4 =
                         self.data[src_offset + ((x * 2) + 1) + ((y * 2) + 1) * src_width];
-                    let pixel_output = average_four_pixels(
-                        pixel_sample1,
-                        pixel_sample2,
-                        pixel_sample3,
-                        pixel_sample4,
-                        0.25,
-                        0.25,
-                        0.25,
-                        0.25,
-                    );
+                    let pixel_output =
+                        (pixel_sample1 + pixel_sample2 + pixel_sample3 + pixel_sample4) * 0.25;
 
                     // Write it to the output buffer
                     new_mipmap.push(pixel_output);
@@ -311,34 +465,150 @@ impl Texture {
     }
 }
 
+// Converts an 8-bit RGBA texel to linear RGBA in 0..1
+fn decode_texel(texel: [u8; 4], colour_space: ColourSpace) -> Vec4 {
+    let texel = Vec4::from(texel.map(|channel| channel as f32 / 255.0));
+    match colour_space {
+        ColourSpace::Linear => texel,
+        ColourSpace::Srgb => glam::vec4(
+            srgb_to_linear(texel.x),
+            srgb_to_linear(texel.y),
+            srgb_to_linear(texel.z),
+            texel.w,
+        ),
+    }
+}
+
 fn average_four_pixels(
-    pixel_sample1: u32,
-    pixel_sample2: u32,
-    pixel_sample3: u32,
-    pixel_sample4: u32,
+    pixel_sample1: Vec4,
+    pixel_sample2: Vec4,
+    pixel_sample3: Vec4,
+    pixel_sample4: Vec4,
     weight1: f32,
     weight2: f32,
     weight3: f32,
     weight4: f32,
-) -> u32 {
-    let r = (((pixel_sample1 >> 0) & 0xFF) as f32) * weight1
-        + (((pixel_sample2 >> 0) & 0xFF) as f32) * weight2
-        + (((pixel_sample3 >> 0) & 0xFF) as f32) * weight3
-        + (((pixel_sample4 >> 0) & 0xFF) as f32) * weight4;
-    let g = (((pixel_sample1 >> 8) & 0xFF) as f32) * weight1
-        + (((pixel_sample2 >> 8) & 0xFF) as f32) * weight2
-        + (((pixel_sample3 >> 8) & 0xFF) as f32) * weight3
-        + (((pixel_sample4 >> 8) & 0xFF) as f32) * weight4;
-    let b = (((pixel_sample1 >> 16) & 0xFF) as f32) * weight1
-        + (((pixel_sample2 >> 16) & 0xFF) as f32) * weight2
-        + (((pixel_sample3 >> 16) & 0xFF) as f32) * weight3
-        + (((pixel_sample4 >> 16) & 0xFF) as f32) * weight4;
-    let a = (((pixel_sample1 >> 24) & 0xFF) as f32) * weight1
-        + (((pixel_sample2 >> 24) & 0xFF) as f32) * weight2
-        + (((pixel_sample3 >> 24) & 0xFF) as f32) * weight3
-        + (((pixel_sample4 >> 24) & 0xFF) as f32) * weight4;
-    ((r.clamp(0.0, 255.0) as u32) << 0)
-        | ((g.clamp(0.0, 255.0) as u32) << 8)
-        | ((b.clamp(0.0, 255.0) as u32) << 16)
-        | ((a.clamp(0.0, 255.0) as u32) << 24)
+) -> Vec4 {
+    pixel_sample1 * weight1
+        + pixel_sample2 * weight2
+        + pixel_sample3 * weight3
+        + pixel_sample4 * weight4
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn bilinear_sampling_on_whole_texels() {
+        let texture = Texture {
+            width: 2,
+            height: 2,
+            depth: 4,
+            data: vec![Vec4::ZERO, Vec4::ONE, Vec4::ONE, Vec4::ZERO],
+            mipmap_offsets: vec![0],
+        };
+        // u = 0.5 lands exactly on texel 1, and v halfway between the rows, which wrap
+        let sample = texture.rgba_at_level(0.5, 0.25, 0, &FilterMode::Linear);
+        assert_eq!(sample, Vec4::splat(0.5));
+        let sample = texture.rgba_at_level(0.5, 0.5, 0, &FilterMode::Linear);
+        assert_eq!(sample, Vec4::ZERO);
+    }
+
+    // 64 texels in a row, where every fourth one starting at 2 is white. With the footprint of a
+    // pixel covering the whole row, 16 probes land exactly on the white texels
+    pub(crate) fn stripes() -> MaterialTexture {
+        let mut stripes = MaterialTexture::solid(Vec4::ZERO);
+        stripes.texture.width = 64;
+        stripes.texture.data = (0..64)
+            .map(|x| if x % 4 == 2 { Vec4::ONE } else { Vec4::ZERO })
+            .collect();
+        stripes.sampler.wrap_mode_s = WrapMode::Repeat;
+        stripes.sampler.max_anisotropy = MAX_ANISOTROPY;
+        stripes
+    }
+
+    #[test]
+    fn mip_level_applies_bias_and_clamps() {
+        // 8x8 with mipmaps down to 1x1, and a footprint of 4 texels, which is level 2
+        let mut texture = MaterialTexture::solid(Vec4::ONE);
+        texture.texture.width = 8;
+        texture.texture.height = 8;
+        texture.texture.data = vec![Vec4::ONE; 64];
+        texture.texture.generate_mipmaps();
+        let mut sampler = texture.sampler;
+        sampler.mipmap_enabled = true;
+        let level = |sampler: &Sampler| {
+            let (level, is_mag) =
+                texture
+                    .texture
+                    .mip_level(sampler, glam::vec2(0.5, 0.0), glam::vec2(0.0, 0.25));
+            assert!(!is_mag);
+            level
+        };
+
+        assert_eq!(level(&sampler), 2.0);
+        sampler.lod_bias = 0.5;
+        assert_eq!(level(&sampler), 2.5);
+        sampler.lod_bias = 10.0;
+        assert_eq!(level(&sampler), 3.0);
+        sampler.lod_bias = 0.0;
+        sampler.max_lod = 1.0;
+        assert_eq!(level(&sampler), 1.0);
+        sampler.max_lod = 1000.0;
+        sampler.min_lod = 2.75;
+        assert_eq!(level(&sampler), 2.75);
+        // An empty range doesn't panic, max_lod wins
+        sampler.max_lod = 1.5;
+        assert_eq!(level(&sampler), 1.5);
+    }
+
+    #[test]
+    fn trilinear_filtering_blends_the_nearest_mip_levels() {
+        // 8x8 with mipmaps down to 1x1, each level filled with its own number
+        let mut texture = MaterialTexture::solid(Vec4::ONE);
+        texture.texture.width = 8;
+        texture.texture.height = 8;
+        texture.texture.data = vec![Vec4::ONE; 64];
+        texture.texture.generate_mipmaps();
+        let offsets = texture.texture.mipmap_offsets.clone();
+        for (level, &offset) in offsets.iter().enumerate() {
+            let size = (8 >> level) * (8 >> level);
+            texture.texture.data[offset..offset + size].fill(Vec4::splat(level as f32));
+        }
+        let mut sampler = texture.sampler;
+        sampler.mipmap_enabled = true;
+        sampler.filter_mode_min = FilterMode::Linear;
+        // A footprint of 4 texels is level 2, the bias takes it to 1.25
+        sampler.lod_bias = -0.75;
+        let sample = |sampler: &Sampler| {
+            let duv = glam::vec2(0.5, 0.0);
+            texture
+                .texture
+                .sample(Vec2::splat(0.5), duv, duv.perp(), 1.0, sampler)
+                .x
+        };
+
+        sampler.filter_mode_mipmap = FilterMode::Linear;
+        assert_eq!(sample(&sampler), 1.25);
+        sampler.filter_mode_mipmap = FilterMode::Point;
+        assert_eq!(sample(&sampler), 1.0);
+        // Whole levels don't blend with the next one
+        sampler.filter_mode_mipmap = FilterMode::Linear;
+        sampler.lod_bias = 0.0;
+        assert_eq!(sample(&sampler), 2.0);
+    }
+
+    #[test]
+    fn anisotropy_is_clamped() {
+        let MaterialTexture { texture, sampler } = stripes();
+        let sample = |max_anisotropy| {
+            let duv_dy = glam::vec2(0.0, 1e-6);
+            texture.sample(Vec2::splat(0.5), Vec2::X, duv_dy, max_anisotropy, &sampler)
+        };
+        assert_eq!(sample(MAX_ANISOTROPY), Vec4::ONE);
+        assert_eq!(sample(1e6), Vec4::ONE);
+        assert_eq!(sample(f32::NAN), Vec4::ZERO);
+        assert_eq!(sample(0.0), Vec4::ZERO);
+    }
 }
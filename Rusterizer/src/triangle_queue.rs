@@ -1,11 +1,268 @@
-use crate::structs::Vertex;
+use glam::{Mat4, Vec3, Vec4};
 
-pub struct TriangleQueueEntry {
-    pub v0: Vertex,
-    pub v1: Vertex,
-    pub v2: Vertex,
+use crate::mesh::{Mesh, Model};
+use crate::pipeline::PipelineState;
+use crate::render_target::RenderTarget;
+use crate::rendering::Renderer;
+use crate::structs::{FragIn, Transform};
+use crate::texture::Material;
+
+// A triangle after vertex shading, clipping and the perspective divide
+#[derive(Clone, Copy)]
+pub struct TriangleQueueEntry<'a> {
+    pub v0: FragIn,
+    pub v1: FragIn,
+    pub v2: FragIn,
+    pub material: Option<&'a Material>,
+    pub pipeline: PipelineState,
+}
+
+// For every tile on screen, the indices of the triangles whose bounding box touches it
+pub struct TileBins {
+    pub tile_size: usize,
+    pub tiles_x: usize,
+    pub tiles_y: usize,
+    pub bins: Vec<Vec<usize>>,
 }
 
-pub struct RenderQueue {
+impl TileBins {
+    pub fn new(width: usize, height: usize, tile_size: usize) -> Self {
+        let tiles_x = width.div_ceil(tile_size);
+        let tiles_y = height.div_ceil(tile_size);
+        TileBins {
+            tile_size,
+            tiles_x,
+            tiles_y,
+            bins: vec![Vec::new(); tiles_x * tiles_y],
+        }
+    }
+
+    // Bounds are in pixels and inclusive
+    pub fn insert(
+        &mut self,
+        triangle: usize,
+        x_min: usize,
+        y_min: usize,
+        x_max: usize,
+        y_max: usize,
+    ) {
+        for tile_y in (y_min / self.tile_size)..=(y_max / self.tile_size).min(self.tiles_y - 1) {
+            for tile_x in (x_min / self.tile_size)..=(x_max / self.tile_size).min(self.tiles_x - 1)
+            {
+                self.bins[tile_x + tile_y * self.tiles_x].push(triangle);
+            }
+        }
+    }
+}
+
+pub struct DrawCommand<'a> {
+    pub mesh: &'a Mesh,
+    pub model_matrix: Mat4,
+    pub material: Option<&'a Material>,
+    // Draws with blending are drawn after all opaque ones, furthest first
+    pub pipeline: PipelineState,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FrameStats {
+    pub draws_submitted: usize,
+    pub draws_culled: usize,
+    pub triangles_submitted: usize,
+    pub triangles_rasterized: usize,
+}
+
+// Collects draws over a frame, so they can be culled, sorted and rasterized together in flush()
+#[derive(Default)]
+pub struct RenderQueue<'a> {
+    pub draws: Vec<DrawCommand<'a>>,
+}
+
+impl<'a> RenderQueue<'a> {
+    pub fn new() -> Self {
+        RenderQueue { draws: Vec::new() }
+    }
+
+    pub fn submit(&mut self, draw: DrawCommand<'a>) {
+        self.draws.push(draw);
+    }
+
+    pub fn submit_mesh(
+        &mut self,
+        mesh: &'a Mesh,
+        model_matrix: &Transform,
+        material: Option<&'a Material>,
+    ) {
+        self.submit(DrawCommand {
+            mesh,
+            model_matrix: model_matrix.trans_matrix(),
+            material,
+            pipeline: PipelineState::for_material(material),
+        });
+    }
+
+    pub fn submit_model(
+        &mut self,
+        renderer: &'a Renderer,
+        model: &'a Model,
+        model_matrix: &Transform,
+    ) {
+        for (material_name, mesh) in &model.meshes {
+            self.submit_mesh(mesh, model_matrix, renderer.material(material_name));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.draws.clear();
+    }
+
+    // Culls, sorts and rasterizes everything that was submitted, leaving the queue empty
+    pub fn flush(&mut self, renderer: &Renderer, target: &mut RenderTarget) -> FrameStats {
+        let mut stats = FrameStats {
+            draws_submitted: self.draws.len(),
+            ..Default::default()
+        };
+        let view_projection = renderer.projection_matrix * renderer.view_matrix;
 
-}
\ No newline at end of file
+        // Cull whole draws against the frustum, and find out how far away they are for sorting
+        let mut opaque = Vec::new();
+        let mut blended = Vec::new();
+        let mut culled = Vec::new();
+        for draw in self.draws.drain(..) {
+            let (min, max) = draw.mesh.bounds();
+            if draw.mesh.verts.is_empty() {
+                stats.draws_culled += 1;
+                continue;
+            }
+            if !bounds_in_frustum(min, max, &(view_projection * draw.model_matrix)) {
+                // Still casts shadows into view
+                stats.draws_culled += 1;
+                culled.push(draw);
+                continue;
+            }
+            let centre = (min + max) * 0.5;
+            let distance = -(renderer.view_matrix * draw.model_matrix)
+                .transform_point3(centre)
+                .z;
+            if draw.pipeline.blend.is_some() {
+                blended.push((distance, draw));
+            } else {
+                opaque.push((distance, draw));
+            }
+        }
+
+        // Front to back for opaque draws so the depth test rejects as much as possible,
+        // back to front for blended draws so they composite correctly
+        opaque.sort_by(|a, b| a.0.total_cmp(&b.0));
+        blended.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let draws: Vec<&DrawCommand> = opaque
+            .iter()
+            .chain(blended.iter())
+            .map(|(_, draw)| draw)
+            .collect();
+        stats.triangles_submitted = draws.iter().map(|draw| draw.mesh.verts.len() / 3).sum();
+        let casters: Vec<&DrawCommand> = draws.iter().copied().chain(culled.iter()).collect();
+        stats.triangles_rasterized = renderer.draw_commands(&draws, &casters, target);
+        // Once for the whole frame, and before the wireframes so they stay on top
+        renderer.resolve_debug_view(target);
+        renderer.draw_wireframes(&draws, target);
+        stats
+    }
+}
+
+// An AABB is outside the frustum when all 8 of its corners are outside the same clip plane
+fn bounds_in_frustum(min: Vec3, max: Vec3, model_view_projection: &Mat4) -> bool {
+    let corners = [
+        glam::vec3(min.x, min.y, min.z),
+        glam::vec3(max.x, min.y, min.z),
+        glam::vec3(min.x, max.y, min.z),
+        glam::vec3(max.x, max.y, min.z),
+        glam::vec3(min.x, min.y, max.z),
+        glam::vec3(max.x, min.y, max.z),
+        glam::vec3(min.x, max.y, max.z),
+        glam::vec3(max.x, max.y, max.z),
+    ]
+    .map(|corner| *model_view_projection * corner.extend(1.0));
+
+    let outside = |test: fn(&Vec4) -> bool| corners.iter().all(test);
+    !(outside(|c| c.x < -c.w)
+        || outside(|c| c.x > c.w)
+        || outside(|c| c.y < -c.w)
+        || outside(|c| c.y > c.w)
+        || outside(|c| c.z < 0.0)
+        || outside(|c| c.z > c.w))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec2;
+
+    use crate::depth::DepthConvention;
+    use crate::rendering::tests::{coloured_quad, SQUARE};
+    use crate::rendering::DebugView;
+
+    // Looking down -z with a 90 degree field of view, counting overdraw
+    fn renderer() -> Renderer {
+        let mut renderer = Renderer::new();
+        renderer.debug_view = DebugView::Overdraw;
+        renderer.set_projection_matrix(DepthConvention::Standard.perspective(
+            std::f32::consts::FRAC_PI_2,
+            1.0,
+            0.1,
+            Some(100.0),
+        ));
+        renderer
+    }
+
+    fn overdraw_at_centre(target: &RenderTarget) -> u32 {
+        target.colour_buffer[target.index(32, 32).unwrap()] >> 24
+    }
+
+    #[test]
+    fn opaque_draws_are_drawn_front_to_back() {
+        let renderer = renderer();
+        // Both cover the same part of the screen
+        let near = coloured_quad(SQUARE, -2.0, Vec4::ONE);
+        let far = coloured_quad(SQUARE.map(|corner| corner * 2.0), -4.0, Vec4::ONE);
+
+        // Drawn in submission order the near quad draws over the far one
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        for mesh in [&far, &near] {
+            renderer.draw_mesh(mesh, &Transform::default(), &mut target, None);
+        }
+        assert_eq!(overdraw_at_centre(&target), 2);
+
+        // Sorted, the far quad fails the depth test
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        let mut queue = RenderQueue::new();
+        for mesh in [&far, &near] {
+            queue.submit_mesh(mesh, &Transform::default(), None);
+        }
+        queue.flush(&renderer, &mut target);
+        assert_eq!(overdraw_at_centre(&target), 1);
+        assert!(queue.draws.is_empty());
+    }
+
+    #[test]
+    fn draws_outside_the_frustum_are_culled() {
+        let renderer = renderer();
+        let in_front = coloured_quad(SQUARE, -2.0, Vec4::ONE);
+        let behind = coloured_quad(SQUARE, 2.0, Vec4::ONE);
+        let off_to_the_side =
+            coloured_quad(SQUARE.map(|corner| corner + Vec2::X * 5.0), -2.0, Vec4::ONE);
+        let empty = Mesh { verts: Vec::new() };
+
+        let mut target = RenderTarget::new(64, 64, renderer.depth_convention);
+        let mut queue = RenderQueue::new();
+        for mesh in [&in_front, &behind, &off_to_the_side, &empty] {
+            queue.submit_mesh(mesh, &Transform::default(), None);
+        }
+        let stats = queue.flush(&renderer, &mut target);
+        assert_eq!(stats.draws_submitted, 4);
+        assert_eq!(stats.draws_culled, 3);
+        assert_eq!(stats.triangles_submitted, 2);
+        assert_eq!(stats.triangles_rasterized, 2);
+        assert_eq!(overdraw_at_centre(&target), 1);
+    }
+}